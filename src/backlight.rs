@@ -0,0 +1,41 @@
+use std::fs;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+
+// Linux sysfs backlight, e.g. /sys/class/backlight/backlight
+#[derive(Clone)]
+pub struct Backlight {
+    path: PathBuf
+}
+
+impl Backlight {
+    pub fn open(path: &str) -> Option<Self> {
+        if path.is_empty() {
+            return None;
+        }
+        Some(Self { path: PathBuf::from(path) })
+    }
+
+    // brightness in percent
+    pub fn set_brightness(&self, percent: u8) -> Result<()> {
+        let max = self.read("max_brightness")?;
+        let value = max * u32::from(percent.min(100)) / 100;
+        self.write("brightness", value)
+    }
+
+    pub fn set_power(&self, on: bool) -> Result<()> {
+        // FB_BLANK_UNBLANK and FB_BLANK_POWERDOWN
+        self.write("bl_power", if on { 0 } else { 4 })
+    }
+
+    fn read(&self, name: &str) -> Result<u32> {
+        let path = self.path.join(name);
+        let value = fs::read_to_string(&path).with_context(|| format!("Couldn't read {:?}", path))?;
+        value.trim().parse().with_context(|| format!("Invalid value in {:?}", path))
+    }
+
+    fn write(&self, name: &str, value: u32) -> Result<()> {
+        let path = self.path.join(name);
+        fs::write(&path, value.to_string()).with_context(|| format!("Couldn't write {:?}", path))
+    }
+}
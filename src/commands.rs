@@ -0,0 +1,177 @@
+use std::thread;
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+use json::{object, JsonValue};
+use crate::Config;
+use crate::backlight::Backlight;
+use crate::connector::MQTTPublisher;
+use crate::snapshot::Snapshots;
+use crate::ui::WindowUpdater;
+use crate::video::VideoPlayer;
+
+const DEFAULT_MESSAGE_TIMEOUT_S: u64 = 10;
+
+pub fn command_topic(config: &Config) -> String {
+    config.mqtt_display_topic.clone() + "/command"
+}
+
+pub fn response_topic(config: &Config) -> String {
+    config.mqtt_display_topic.clone() + "/response"
+}
+
+// Remote control of the display. Commands are JSON objects with optional "id" which is sent back in the response:
+//  {"command": "skin", "value": "day" | "night"}
+//  {"command": "video", "action": "pause" | "resume" | "toggle"}
+//  {"command": "video_url", "url": "https://..."}
+//  {"command": "brightness", "value": 0..100}
+//  {"command": "blank", "value": true | false}
+//  {"command": "message", "text": "Dinner is ready", "timeout_s": 10}
+//  {"command": "snapshot"}, the response has "path" of the saved file
+//  {"command": "recording", "action": "start" | "stop" | "toggle"}
+//  {"command": "recordings"}, the response has "files" with names of the recorded segments
+//  {"command": "play_recording", "file": "rec_2024-05-01_12-00-00.mkv"}, the latest segment without "file"
+// Response: {"id": 1, "command": "blank", "ok": true} or {"id": 1, "command": "blank", "ok": false, "error": "..."}
+pub struct CommandHandler {
+    topic: String,
+    response_topic: String,
+    video: VideoPlayer,
+    publisher: MQTTPublisher,
+    backlight: Option<Backlight>,
+    snapshots: Option<Snapshots>
+}
+
+impl CommandHandler {
+    pub fn new(config: &Config, video: VideoPlayer, publisher: MQTTPublisher, backlight: Option<Backlight>,
+               snapshots: Option<Snapshots>) -> Self {
+        Self {
+            topic: command_topic(config),
+            response_topic: response_topic(config),
+            video,
+            publisher,
+            backlight,
+            snapshots
+        }
+    }
+
+    pub fn is_command_topic(&self, topic: &str) -> bool {
+        self.topic == topic
+    }
+
+    pub fn handle(&mut self, updater: WindowUpdater, payload: &JsonValue) {
+        let respond = self.responder(payload);
+        match payload["command"].as_str() {
+            // encoding of the image takes time, the MQTT thread isn't blocked by it
+            Some("snapshot") => {
+                let snapshots = self.snapshots.clone();
+                thread::spawn(move || respond(take_snapshot(snapshots)));
+            },
+            // state of the pipeline is changed on the video control thread
+            Some("video") => {
+                let action = payload["action"].as_str().map(str::to_owned);
+                self.video.control(move |video| respond(control_video(video, action.as_deref())));
+            },
+            Some("video_url") => match payload["url"].as_str() {
+                Some(url) => {
+                    let url = url.to_owned();
+                    self.video.control(move |video| respond(video.set_uri(&url).map(|_| JsonValue::Null)));
+                },
+                None => respond(Err(anyhow!("no url"))),
+            },
+            Some("play_recording") => {
+                let file = payload["file"].as_str().map(str::to_owned);
+                self.video.control(move |video| {
+                    respond(video.play_recording(file.as_deref()).map(|file| object! { file: file }))
+                });
+            },
+            _ => respond(self.execute(&updater, payload)),
+        }
+    }
+
+    // Sends the response to the command, it can be called from other threads
+    fn responder(&self, payload: &JsonValue) -> impl FnOnce(Result<JsonValue>) + Send + 'static {
+        let mut publisher = self.publisher.clone();
+        let response_topic = self.response_topic.clone();
+        let payload = payload.clone();
+        move |result| publish_response(&mut publisher, &response_topic, &payload, result)
+    }
+
+    // Returns fields added to the response
+    fn execute(&mut self, updater: &WindowUpdater, payload: &JsonValue) -> Result<JsonValue> {
+        match payload["command"].as_str() {
+            Some("skin") => match payload["value"].as_str() {
+                Some("day") => updater.update_day_skin(true),
+                Some("night") => updater.update_day_skin(false),
+                _ => bail!("value should be \"day\" or \"night\""),
+            },
+            Some("brightness") => {
+                let value = payload["value"].as_u8().filter(|value| *value <= 100)
+                    .ok_or_else(|| anyhow!("value should be 0..100"))?;
+                match &self.backlight {
+                    Some(backlight) => backlight.set_brightness(value)?,
+                    None => updater.update_dim(1.0 - f32::from(value) / 100.0),
+                }
+            },
+            Some("blank") => {
+                let blank = payload["value"].as_bool().ok_or_else(|| anyhow!("value should be true or false"))?;
+                if let Some(backlight) = &self.backlight {
+                    backlight.set_power(!blank)?;
+                }
+                updater.update_blank(blank);
+            },
+            Some("message") => {
+                let text = payload["text"].as_str().ok_or_else(|| anyhow!("no text"))?;
+                let timeout_s = payload["timeout_s"].as_u64().unwrap_or(DEFAULT_MESSAGE_TIMEOUT_S);
+                updater.show_message(text.to_owned(), Duration::from_secs(timeout_s));
+            },
+            Some("recording") => match payload["action"].as_str() {
+                Some("start") => self.video.set_recording(true)?,
+                Some("stop") => self.video.set_recording(false)?,
+                Some("toggle") => self.video.toggle_recording()?,
+                _ => bail!("action should be \"start\", \"stop\" or \"toggle\""),
+            },
+            Some("recordings") => return Ok(object! { files: self.video.recordings() }),
+            Some(command) => bail!("unknown command {:?}", command),
+            None => bail!("no command"),
+        }
+        Ok(JsonValue::Null)
+    }
+}
+
+fn control_video(video: &VideoPlayer, action: Option<&str>) -> Result<JsonValue> {
+    match action {
+        Some("pause") => video.set_playing(false)?,
+        Some("resume") => video.set_playing(true)?,
+        Some("toggle") => video.toggle_pause_play()?,
+        _ => bail!("action should be \"pause\", \"resume\" or \"toggle\""),
+    }
+    Ok(JsonValue::Null)
+}
+
+fn take_snapshot(snapshots: Option<Snapshots>) -> Result<JsonValue> {
+    let snapshots = snapshots.ok_or_else(|| anyhow!("snapshots are disabled"))?;
+    let path = snapshots.take()?;
+    Ok(object! { path: path.to_string_lossy().as_ref() })
+}
+
+fn publish_response(publisher: &mut MQTTPublisher, response_topic: &str, payload: &JsonValue, result: Result<JsonValue>) {
+    let mut response = object! {
+        command: payload["command"].clone(),
+        ok: result.is_ok()
+    };
+    if payload.has_key("id") {
+        response["id"] = payload["id"].clone();
+    }
+    match result {
+        Ok(JsonValue::Object(details)) => {
+            for (key, value) in details.iter() {
+                response[key] = value.clone();
+            }
+        },
+        Ok(_) => {},
+        Err(e) => {
+            println!("Command {} failed: {}", payload.dump(), e);
+            response["error"] = e.to_string().into();
+        },
+    }
+    publisher.publish(response_topic, response.dump(), false);
+}
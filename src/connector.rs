@@ -1,65 +1,243 @@
-use std::thread;
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::{fmt, fs, panic, thread};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
 use std::time::Duration;
-use rumqttc::{MqttOptions, Event, Incoming, Client, QoS};
-use json::*;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rumqttc::{MqttOptions, Event, Incoming, Client, QoS, Transport, Key, RecvTimeoutError, LastWill};
 use crate::Config;
+use crate::decoder::PayloadFormat;
+use crate::status;
 
+// how long the connection is polled before checking the stop flag, it's the same as rumqttc connection timeout
+const POLL_TIMEOUT: Duration = Duration::from_secs(5);
+// delays between reconnection attempts
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// how many characters of a malformed payload are logged
+const MAX_LOGGED_PAYLOAD: usize = 64;
+
+// number of malformed payloads per topic
+pub type PayloadErrors = Arc<Mutex<HashMap<String, u64>>>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    // the time when the connection was lost
+    Disconnected(DateTime<Utc>)
+}
+
+
+// String which is never printed, e.g. in the config dump
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "***")
+    }
+}
 
 pub struct MQTTConnector {
     pub client: rumqttc::Client,
     handle: Option<thread::JoinHandle<()>>,
-    stop_flag: Arc<AtomicBool>
+    stop_flag: Arc<AtomicBool>,
+    subscriber: MQTTSubscriber,
+    payload_errors: PayloadErrors
+}
+
+// Handle for publishing from other threads, it never blocks
+#[derive(Clone)]
+pub struct MQTTPublisher {
+    client: rumqttc::Client
+}
+
+impl MQTTPublisher {
+    pub fn publish(&mut self, topic: &str, payload: String, retain: bool) {
+        if let Err(e) = self.client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            println!("Couldn't publish to MQTT topic {:?}: {:?}", topic, e);
+        }
+    }
+}
+
+// Handle for subscribing from other threads. It never blocks, so it can be used also in the data callbacks
+// which are called from the connection thread.
+#[derive(Clone)]
+pub struct MQTTSubscriber {
+    client: rumqttc::Client,
+    // subscribed topics with format of their payloads, they are subscribed again after reconnection
+    topics: Arc<Mutex<Vec<(String, PayloadFormat)>>>,
+    // subscriptions which didn't fit into the full requests channel yet
+    pending: Arc<Mutex<Vec<String>>>
+}
+
+impl MQTTSubscriber {
+    fn new(client: Client) -> Self {
+        Self { client, topics: Arc::new(Mutex::new(Vec::new())), pending: Arc::new(Mutex::new(Vec::new())) }
+    }
+    pub fn subscribe(&mut self, topic: &str, format: PayloadFormat) {
+        self.topics.lock().expect("Couldn't lock MQTT topics").push((topic.to_owned(), format));
+        self.pending.lock().expect("Couldn't lock MQTT topics").push(topic.to_owned());
+        self.send_pending();
+    }
+    // The broker forgets subscriptions of clean session after disconnection
+    fn resubscribe(&mut self) {
+        let topics: Vec<String> = self.topics.lock().expect("Couldn't lock MQTT topics").iter()
+            .map(|(topic, _)| topic.clone())
+            .collect();
+        println!("Subscribing again to {} topics", topics.len());
+        *self.pending.lock().expect("Couldn't lock MQTT topics") = topics;
+        self.send_pending();
+    }
+    fn send_pending(&mut self) {
+        let mut pending = self.pending.lock().expect("Couldn't lock MQTT topics");
+        while let Some(topic) = pending.first() {
+            if self.client.try_subscribe(topic.clone(), QoS::AtLeastOnce).is_err() {
+                // it's tried again by the connection thread
+                break;
+            }
+            pending.remove(0);
+        }
+    }
+    // Format of the first subscription matching the topic, JSON by default
+    fn payload_format(&self, topic: &str) -> PayloadFormat {
+        self.topics.lock().expect("Couldn't lock MQTT topics").iter()
+            .find(|(filter, _)| rumqttc::matches(topic, filter))
+            .map(|(_, format)| *format)
+            .unwrap_or_default()
+    }
 }
 
 impl MQTTConnector {
-    pub fn new<F>(name: &str, config: Arc<Config>, callback: F) -> Result<Self>
+    pub fn new<F, S>(name: &str, config: Arc<Config>, callback: F, state_callback: S) -> Result<Self>
     where
-        F: Fn(String, json::JsonValue) + Send + 'static
+        F: Fn(String, json::JsonValue) + Send + 'static,
+        S: Fn(ConnectionState) + Send + 'static
     {
         let mut mqttoptions = MqttOptions::new(name, &config.mqtt_host, config.mqtt_port);
         mqttoptions.set_keep_alive(Duration::from_secs(config.mqtt_keep_alive.into()));
+        if let Some(username) = &config.mqtt_username {
+            mqttoptions.set_credentials(username, read_password(&config)?);
+        }
+        if let Some(transport) = make_tls_transport(&config)? {
+            mqttoptions.set_transport(transport);
+        }
+        // the broker announces the display as offline when the connection is lost
+        let availability_topic = status::availability_topic(&config);
+        mqttoptions.set_last_will(LastWill::new(&availability_topic, status::OFFLINE, QoS::AtLeastOnce, true));
         println!("Connecting to MQTT broker...");
 
         let (client, mut connection) = Client::new(mqttoptions, 10);
 
         let stop_flag_orig = Arc::new(AtomicBool::new(false));
         let stop_flag = stop_flag_orig.clone();
+        let subscriber_orig = MQTTSubscriber::new(client.clone());
+        let mut subscriber = subscriber_orig.clone();
+        let mut publisher = MQTTPublisher { client: client.clone() };
+        let payload_errors_orig = PayloadErrors::default();
+        let payload_errors = payload_errors_orig.clone();
 
         // Connection handler thread
         let handle = thread::spawn(move || {
-            // TODO: this won't work if connecions hanged up
-            // replace iter() on some recv (recv_timeout) and handle notifications one by one with checking stop_flag
+            let mut reconnect_delay = MIN_RECONNECT_DELAY;
+            let mut disconnected_since: Option<DateTime<Utc>> = None;
+            state_callback(ConnectionState::Connecting);
+
             while !stop_flag.load(Ordering::Acquire) {
-                // The `EventLoop`/`Connection` must be regularly polled(`.next()` in case of `Connection`) in order
-                //  to send, receive and process packets from the broker, i.e. move ahead.
-                for (_, notification) in connection.iter().enumerate() {
-                    if stop_flag.load(Ordering::Acquire) {
-                        println!("Connector thread: received stop signal, closing...");
+                subscriber.send_pending();
+                // The `EventLoop`/`Connection` must be regularly polled in order to send, receive and process packets
+                //  from the broker, i.e. move ahead. It also reconnects after connection errors.
+                let notification = match connection.recv_timeout(POLL_TIMEOUT) {
+                    Ok(notification) => notification,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        println!("Connector thread: all clients are closed, exiting...");
                         break;
-                    }
+                    },
+                };
 
-                    // println!("MQTT notification = {:?}", notification);
+                // println!("MQTT notification = {:?}", notification);
 
-                    if let Ok(Event::Incoming(Incoming::Publish(packet))) = notification {
+                match notification {
+                    Ok(Event::Incoming(Incoming::ConnAck(_))) => {
+                        println!("Connected to MQTT broker");
+                        reconnect_delay = MIN_RECONNECT_DELAY;
+                        if disconnected_since.take().is_some() {
+                            subscriber.resubscribe();
+                        }
+                        publisher.publish(&availability_topic, status::ONLINE.to_owned(), true);
+                        state_callback(ConnectionState::Connected);
+                    },
+                    Ok(Event::Incoming(Incoming::Publish(packet))) => {
                         let payload = String::from_utf8_lossy(&packet.payload);
-                        let payload = json::parse(payload.as_ref()).unwrap();
                         println!("received packet with topic = {:?}", packet.topic);
-                        callback(packet.topic, payload);
-                    }
+                        let json_payload = match subscriber.payload_format(&packet.topic).decode(payload.as_ref()) {
+                            Ok(json_payload) => json_payload,
+                            // an empty retained message clears the topic, e.g. removes a discovered sensor
+                            Err(_) if packet.payload.is_empty() => json::JsonValue::Null,
+                            Err(e) => {
+                                let errors = count_payload_error(&payload_errors, &packet.topic);
+                                println!("Malformed payload on topic {:?} ({} errors so far): {}, payload: {:?}",
+                                         packet.topic, errors, e, truncate(&payload, MAX_LOGGED_PAYLOAD));
+                                continue;
+                            },
+                        };
+                        // unexpected content mustn't kill the connection thread
+                        let topic = packet.topic.clone();
+                        if panic::catch_unwind(panic::AssertUnwindSafe(|| callback(topic, json_payload))).is_err() {
+                            let errors = count_payload_error(&payload_errors, &packet.topic);
+                            println!("Failed to handle payload on topic {:?} ({} errors so far), payload: {:?}",
+                                     packet.topic, errors, truncate(&payload, MAX_LOGGED_PAYLOAD));
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(e) => {
+                        println!("MQTT connection error: {:?}, reconnecting in {:?}...", e, reconnect_delay);
+                        let since = *disconnected_since.get_or_insert_with(Utc::now);
+                        state_callback(ConnectionState::Disconnected(since));
+                        sleep_unless_stopped(reconnect_delay, &stop_flag);
+                        reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+                    },
                 }
             }
+            println!("Connector thread: closed");
         });
 
         let connector = Self {
             client,
             handle: Some(handle),
-            stop_flag: stop_flag_orig
+            stop_flag: stop_flag_orig,
+            subscriber: subscriber_orig,
+            payload_errors: payload_errors_orig
         };
         Ok(connector)
     }
-    pub fn subscribe_client(&mut self, topic: &String) {
-        self.client.subscribe(topic, QoS::AtLeastOnce).unwrap();
+    pub fn subscribe_client(&mut self, topic: &str, format: PayloadFormat) {
+        self.subscriber.subscribe(topic, format);
+    }
+    pub fn subscriber(&self) -> MQTTSubscriber {
+        self.subscriber.clone()
+    }
+    pub fn publisher(&self) -> MQTTPublisher {
+        MQTTPublisher { client: self.client.clone() }
+    }
+    pub fn payload_errors(&self) -> PayloadErrors {
+        self.payload_errors.clone()
     }
 }
 
@@ -74,3 +252,70 @@ impl Drop for MQTTConnector {
         }
     }
 }
+
+fn count_payload_error(payload_errors: &Mutex<HashMap<String, u64>>, topic: &str) -> u64 {
+    let mut payload_errors = payload_errors.lock().expect("Couldn't lock MQTT payload errors");
+    let errors = payload_errors.entry(topic.to_owned()).or_insert(0);
+    *errors += 1;
+    *errors
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => text[..index].to_owned() + "...",
+        None => text.to_owned(),
+    }
+}
+
+fn sleep_unless_stopped(duration: Duration, stop_flag: &AtomicBool) {
+    let step = Duration::from_millis(100);
+    let mut slept = Duration::ZERO;
+    while slept < duration && !stop_flag.load(Ordering::Acquire) {
+        thread::sleep(step);
+        slept += step;
+    }
+}
+
+fn read_password(config: &Config) -> Result<String> {
+    match (&config.mqtt_password_file, &config.mqtt_password) {
+        (Some(path), _) => {
+            let password = fs::read_to_string(path)
+                .with_context(|| format!("Couldn't read MQTT password file {:?}", path))?;
+            Ok(password.trim_end_matches(['\r', '\n']).to_owned())
+        },
+        (None, Some(password)) => Ok(password.expose().to_owned()),
+        (None, None) => Ok(String::new()),
+    }
+}
+
+// TLS is used when CA certificate is given or MQTT_TLS is set (with the system root certificates)
+fn make_tls_transport(config: &Config) -> Result<Option<Transport>> {
+    let client_auth = match (&config.mqtt_client_cert, &config.mqtt_client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = fs::read(cert_path)
+                .with_context(|| format!("Couldn't read MQTT client certificate {:?}", cert_path))?;
+            let key = fs::read(key_path)
+                .with_context(|| format!("Couldn't read MQTT client key {:?}", key_path))?;
+            // PKCS#1 keys are RSA only, other keys are expected in PKCS#8 format
+            let key_text = String::from_utf8_lossy(&key);
+            if key_text.contains("EC PRIVATE KEY") {
+                bail!("MQTT client key {:?} is a SEC1 EC key, convert it to PKCS#8: \
+                       openssl pkcs8 -topk8 -nocrypt -in key.pem -out key_pkcs8.pem", key_path);
+            }
+            let key = if key_text.contains("RSA PRIVATE KEY") { Key::RSA(key) } else { Key::ECC(key) };
+            Some((cert, key))
+        },
+        (None, None) => None,
+        _ => bail!("Both MQTT_CLIENT_CERT and MQTT_CLIENT_KEY have to be set for client authentication"),
+    };
+
+    match &config.mqtt_ca_cert {
+        Some(ca_path) => {
+            let ca = fs::read(ca_path).with_context(|| format!("Couldn't read MQTT CA certificate {:?}", ca_path))?;
+            Ok(Some(Transport::tls(ca, client_auth, None)))
+        },
+        None if client_auth.is_some() => bail!("MQTT_CA_CERT has to be set for client authentication"),
+        None if config.mqtt_tls => Ok(Some(Transport::tls_with_default_config())),
+        None => Ok(None),
+    }
+}
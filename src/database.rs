@@ -1,6 +1,18 @@
 use std::sync::Arc;
 use crate::Config;
-use std::time::{Duration, Instant};
+use crate::storage::{HistoryStorage, StoredSample, StoredRecord};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use chrono::{DateTime, Utc};
+
+// Raw samples are kept for the last hour
+const RAW_RETENTION_S: i64 = 3600;
+// (resolution, retention) of the downsampled tiers, each one is fed by the previous tier
+const TIERS: [(i64, i64); 3] = [
+    (5 * 60, 24 * 3600),
+    (3600, 7 * 24 * 3600),
+    (6 * 3600, 30 * 24 * 3600)
+];
 
 
 pub struct DataBase {
@@ -13,61 +25,479 @@ impl DataBase {
     pub fn new(config: Arc<Config>) -> Self {
         DataBase {
             indoor_t_history: HistoryContainer::new(config.history_n_elements,
-                                                    Duration::from_secs(config.history_interval_s)),
+                                                    config.indoor_t_aggregation,
+                                                    HistoryStorage::open(&config.history_data_dir, "indoor_t")),
             indoor_rh_history: HistoryContainer::new(config.history_n_elements,
-                                                     Duration::from_secs(config.history_interval_s)),
+                                                     config.indoor_rh_aggregation,
+                                                     HistoryStorage::open(&config.history_data_dir, "indoor_rh")),
             indoor_co2_history: HistoryContainer::new(config.history_n_elements,
-                                                     Duration::from_secs(config.history_interval_s))
+                                                     config.indoor_co2_aggregation,
+                                                     HistoryStorage::open(&config.history_data_dir, "indoor_co2"))
+        }
+    }
+}
+
+// Time range shown on the history charts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryRange {
+    Day,
+    Week,
+    Month
+}
+
+impl HistoryRange {
+    pub fn next(&self) -> Self {
+        match self {
+            HistoryRange::Day => HistoryRange::Week,
+            HistoryRange::Week => HistoryRange::Month,
+            HistoryRange::Month => HistoryRange::Day,
+        }
+    }
+    pub fn label(&self) -> &'static str {
+        match self {
+            HistoryRange::Day => "24H",
+            HistoryRange::Week => "7D",
+            HistoryRange::Month => "30D",
+        }
+    }
+    fn duration_s(&self) -> i64 {
+        match self {
+            HistoryRange::Day => 24 * 3600,
+            HistoryRange::Week => 7 * 24 * 3600,
+            HistoryRange::Month => 30 * 24 * 3600,
         }
     }
 }
 
+// How the samples of one interval are summarized to the history point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Last,
+    Mean,
+    Min,
+    Max,
+    // mean value with min/max band
+    MinMax
+}
+
+impl FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "last" => Ok(Aggregation::Last),
+            "mean" => Ok(Aggregation::Mean),
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "minmax" | "min_max" => Ok(Aggregation::MinMax),
+            _ => Err(format!("Unknown aggregation mode '{}'", s)),
+        }
+    }
+}
+
+// History point: the aggregated value and the band around it (min == max == value if there is no band)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryValue {
+    pub value: f32,
+    pub min: f32,
+    pub max: f32
+}
+
+// Statistics of the samples of one interval
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    pub count: i64,
+    pub sum: f64,
+    pub min: f32,
+    pub max: f32,
+    pub last: f32
+}
+
+impl Bucket {
+    fn new(value: f32) -> Self {
+        Self { count: 1, sum: value.into(), min: value, max: value, last: value }
+    }
+    // `other` has to be newer than `self`
+    fn merge(&mut self, other: &Bucket) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.last = other.last;
+    }
+    fn aggregate(&self, aggregation: Aggregation) -> HistoryValue {
+        let mean = (self.sum / self.count.max(1) as f64) as f32;
+        let value = match aggregation {
+            Aggregation::Last => self.last,
+            Aggregation::Mean | Aggregation::MinMax => mean,
+            Aggregation::Min => self.min,
+            Aggregation::Max => self.max,
+        };
+        match aggregation {
+            Aggregation::MinMax => HistoryValue { value, min: self.min, max: self.max },
+            _ => HistoryValue { value, min: value, max: value },
+        }
+    }
+}
+
+// Downsampled buckets of one resolution, the key is the bucket start in seconds since Unix epoch
+struct Tier {
+    resolution_s: i64,
+    retention_s: i64,
+    buckets: VecDeque<(i64, Bucket)>
+}
+
+impl Tier {
+    fn new((resolution_s, retention_s): (i64, i64)) -> Self {
+        Self { resolution_s, retention_s, buckets: VecDeque::new() }
+    }
+    // Data has to be added in chronological order
+    fn add(&mut self, timestamp: i64, bucket: &Bucket) {
+        let start = timestamp - timestamp.rem_euclid(self.resolution_s);
+        match self.buckets.back_mut() {
+            Some((last_start, last)) if *last_start == start => last.merge(bucket),
+            _ => self.buckets.push_back((start, *bucket)),
+        }
+    }
+    // Removes and returns the buckets which are completely out of the retention time
+    fn take_outdated(&mut self, now: i64) -> Vec<(i64, Bucket)> {
+        let mut outdated = Vec::new();
+        while let Some((start, _)) = self.buckets.front() {
+            if start + self.resolution_s > now - self.retention_s {
+                break;
+            }
+            outdated.extend(self.buckets.pop_front());
+        }
+        outdated
+    }
+}
+
+// Multi-resolution history of one metric: raw samples for the last hour and downsampled tiers
+// for the last day, week and month. Every sample is kept in exactly one tier,
+// it moves to the next (coarser) tier when it gets older than the tier retention time.
 pub struct HistoryContainer {
-    data: Vec<i32>,
-    interval: std::time::Duration,
-    current_time_index: usize,
-    last_timestamp: std::time::Instant
+    samples: VecDeque<StoredSample>,
+    tiers: Vec<Tier>,
+    num_elements: usize,
+    aggregation: Aggregation,
+    storage: Option<HistoryStorage>
 }
 
 impl HistoryContainer {
-    pub fn new(num_elements: usize, interval: std::time::Duration) -> Self {
-        Self {
-            data: vec![0; num_elements],
-            interval,
-            current_time_index: 0,
-            last_timestamp: Instant::now()
+    pub fn new(num_elements: usize, aggregation: Aggregation, storage: Option<HistoryStorage>) -> Self {
+        Self::open_at(num_elements, aggregation, storage, Utc::now())
+    }
+    fn open_at(num_elements: usize, aggregation: Aggregation, storage: Option<HistoryStorage>,
+               now: DateTime<Utc>) -> Self {
+        let mut container = Self {
+            samples: VecDeque::new(),
+            tiers: TIERS.into_iter().map(Tier::new).collect(),
+            num_elements: num_elements.max(2),
+            aggregation,
+            storage
+        };
+        container.restore(now);
+        container
+    }
+    pub fn insert(&mut self, val: f32) {
+        self.insert_at(val, Utc::now());
+    }
+    fn insert_at(&mut self, val: f32, now: DateTime<Utc>) {
+        let sample = (now, val);
+        self.samples.push_back(sample);
+        self.downsample(now.timestamp());
+        self.store(sample);
+    }
+    // Returns `num_elements` aggregated values covering the range, oldest first.
+    // Intervals are aligned to the wall-clock, the last one is the current (incomplete) interval.
+    // Intervals without samples are `None`.
+    pub fn get_history(&self, range: HistoryRange) -> Vec<Option<HistoryValue>> {
+        self.get_history_at(range, Utc::now())
+    }
+    fn get_history_at(&self, range: HistoryRange, now: DateTime<Utc>) -> Vec<Option<HistoryValue>> {
+        let interval_s = (range.duration_s() / (self.num_elements as i64 - 1)).max(1);
+        let first_bucket = now.timestamp().div_euclid(interval_s) - (self.num_elements as i64 - 1);
+        let mut buckets: Vec<Option<Bucket>> = vec![None; self.num_elements];
+        let mut add = |timestamp: i64, bucket: &Bucket| {
+            let index = timestamp.div_euclid(interval_s) - first_bucket;
+            if index < 0 || index as usize >= buckets.len() {
+                return;
+            }
+            match buckets[index as usize].as_mut() {
+                Some(current) => current.merge(bucket),
+                None => buckets[index as usize] = Some(*bucket),
+            }
+        };
+        // from the oldest data to the newest one
+        for tier in self.tiers.iter().rev() {
+            for (start, bucket) in &tier.buckets {
+                add(*start, bucket);
+            }
+        }
+        for (timestamp, value) in &self.samples {
+            add(timestamp.timestamp(), &Bucket::new(*value));
         }
+        buckets.iter().map(|bucket| bucket.map(|bucket| bucket.aggregate(self.aggregation))).collect()
     }
-    pub fn insert(&mut self, val: i32) -> bool {
-        self.data[0] = val;
 
-        if self.last_timestamp.elapsed() >= self.interval {
-            self.last_timestamp = Instant::now();
-            self.current_time_index += 1;
-            self.current_time_index = if self.current_time_index >= self.data.len() {1}
-                                      else {self.current_time_index};
-            self.data[self.current_time_index] = val;
-            return true;
+    // Moves the outdated data to the coarser tiers and drops data older than the last tier
+    fn downsample(&mut self, now: i64) {
+        while let Some((timestamp, value)) = self.samples.front() {
+            if timestamp.timestamp() > now - RAW_RETENTION_S {
+                break;
+            }
+            self.tiers[0].add(timestamp.timestamp(), &Bucket::new(*value));
+            self.samples.pop_front();
         }
-        return false;
+        for index in 0..self.tiers.len() {
+            let outdated = self.tiers[index].take_outdated(now);
+            if let Some(next_tier) = self.tiers.get_mut(index + 1) {
+                for (start, bucket) in outdated {
+                    next_tier.add(start, &bucket);
+                }
+            }
+        }
+    }
+
+    fn records(&self) -> Vec<StoredRecord> {
+        let mut records = Vec::new();
+        for (index, tier) in self.tiers.iter().enumerate() {
+            for (start, bucket) in &tier.buckets {
+                if let Some(start) = DateTime::from_timestamp(*start, 0) {
+                    records.push(StoredRecord::Bucket(index, start, *bucket));
+                }
+            }
+        }
+        records.extend(self.samples.iter().map(|sample| StoredRecord::Sample(*sample)));
+        records
+    }
+    fn store(&mut self, sample: StoredSample) {
+        let max_records = self.samples.len().max(self.num_elements);
+        let appended = match self.storage.as_mut() {
+            Some(storage) => {
+                storage.append(&StoredRecord::Sample(sample));
+                storage.appended()
+            },
+            None => return,
+        };
+        // write the downsampled state from time to time, it also drops the outdated records
+        if appended >= max_records {
+            let records = self.records();
+            if let Some(storage) = self.storage.as_mut() {
+                storage.rewrite(&records);
+            }
+        }
+    }
+    fn restore(&mut self, now: DateTime<Utc>) {
+        let records = match self.storage.as_ref() {
+            Some(storage) => storage.load(),
+            None => return,
+        };
+        // without RTC the clock is wrong until it's synchronized, the stored data is kept as is then
+        let newest = records.iter().map(|record| match record {
+            StoredRecord::Sample((timestamp, _)) => *timestamp,
+            StoredRecord::Bucket(_, start, _) => *start,
+        }).max();
+        let clock_behind = newest.map(|newest| newest > now).unwrap_or(false);
+        for record in records {
+            match record {
+                StoredRecord::Sample(sample) if sample.0 <= now || clock_behind => self.samples.push_back(sample),
+                StoredRecord::Bucket(index, start, bucket) if index < self.tiers.len() => {
+                    self.tiers[index].add(start.timestamp(), &bucket);
+                },
+                _ => {},
+            }
+        }
+        self.downsample(now.timestamp());
+        let records = self.records();
+        if clock_behind {
+            println!("Clock is behind the stored history, {} records are restored without cleanup", records.len());
+            return;
+        }
+        if let Some(storage) = self.storage.as_mut() {
+            storage.rewrite(&records);
+        }
+        println!("Restored {} history records from storage", records.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // aligned to all tier resolutions
+    const T: i64 = 78704 * 6 * 3600;
+
+    fn at(timestamp: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(timestamp, 0).unwrap()
+    }
+
+    fn starts(container: &HistoryContainer, tier: usize) -> Vec<i64> {
+        container.tiers[tier].buckets.iter().map(|(start, _)| *start).collect()
     }
-    pub fn get_history(&self) -> Vec<i32> {
-        let mut history = vec![0; self.data.len()];
-        let last_index = self.data.len() - 1;
-        // copy elements with instant value
-        history[last_index] = self.data[0];
 
-        if self.current_time_index == 0 {
-            return history;
+    fn bucket(values: &[f32]) -> Bucket {
+        let mut bucket = Bucket::new(values[0]);
+        for value in &values[1..] {
+            bucket.merge(&Bucket::new(*value));
         }
+        bucket
+    }
+
+    #[test]
+    fn bucket_merge() {
+        let merged = bucket(&[1.0, 6.0, 2.0]);
+        assert_eq!((merged.count, merged.sum, merged.min, merged.max, merged.last), (3, 9.0, 1.0, 6.0, 2.0));
+        let mut older = bucket(&[4.0, -1.0]);
+        older.merge(&merged);
+        assert_eq!((older.count, older.sum, older.min, older.max, older.last), (5, 12.0, -1.0, 6.0, 2.0));
+    }
+
+    #[test]
+    fn bucket_aggregate() {
+        let merged = bucket(&[1.0, 6.0, 2.0]);
+        let point = |value, min, max| HistoryValue { value, min, max };
+        assert_eq!(merged.aggregate(Aggregation::Last), point(2.0, 2.0, 2.0));
+        assert_eq!(merged.aggregate(Aggregation::Mean), point(3.0, 3.0, 3.0));
+        assert_eq!(merged.aggregate(Aggregation::Min), point(1.0, 1.0, 1.0));
+        assert_eq!(merged.aggregate(Aggregation::Max), point(6.0, 6.0, 6.0));
+        assert_eq!(merged.aggregate(Aggregation::MinMax), point(3.0, 1.0, 6.0));
+    }
+
+    #[test]
+    fn aggregation_from_str() {
+        assert_eq!("MinMax".parse(), Ok(Aggregation::MinMax));
+        assert_eq!("min_max".parse(), Ok(Aggregation::MinMax));
+        assert_eq!("last".parse(), Ok(Aggregation::Last));
+        assert!("median".parse::<Aggregation>().is_err());
+    }
+
+    #[test]
+    fn raw_samples_move_to_first_tier_after_hour() {
+        let mut container = HistoryContainer::new(25, Aggregation::Mean, None);
+        container.insert_at(1.0, at(T));
+        container.insert_at(3.0, at(T + 60));
+        container.insert_at(5.0, at(T + RAW_RETENTION_S - 1));
+        assert_eq!(container.samples.len(), 3);
+        assert!(starts(&container, 0).is_empty());
+
+        container.insert_at(7.0, at(T + RAW_RETENTION_S));
+        assert_eq!(container.samples.len(), 3);
+        assert_eq!(starts(&container, 0), vec![T]);
+        assert_eq!(container.tiers[0].buckets[0].1.count, 1);
+    }
+
+    #[test]
+    fn buckets_move_to_coarser_tiers() {
+        let mut container = HistoryContainer::new(25, Aggregation::Mean, None);
+        container.insert_at(1.0, at(T));
+        container.insert_at(3.0, at(T + 60));
+        container.insert_at(5.0, at(T + 3600));
+
+        // 5 min buckets are kept for 24 h
+        container.downsample(T + 300 + 24 * 3600 - 1);
+        assert_eq!(starts(&container, 0), vec![T, T + 3600]);
+        container.downsample(T + 300 + 24 * 3600);
+        assert_eq!(starts(&container, 0), vec![T + 3600]);
+        assert_eq!(starts(&container, 1), vec![T]);
+        assert_eq!(container.tiers[1].buckets[0].1.count, 2);
+
+        // 1 h buckets are kept for 7 days
+        container.downsample(T + 3600 + 7 * 24 * 3600 - 1);
+        assert_eq!(starts(&container, 1), vec![T, T + 3600]);
+        assert!(starts(&container, 2).is_empty());
+        container.downsample(T + 3600 + 7 * 24 * 3600);
+        assert_eq!(starts(&container, 1), vec![T + 3600]);
+        assert_eq!(starts(&container, 2), vec![T]);
+        container.downsample(T + 2 * 3600 + 7 * 24 * 3600);
+        assert!(starts(&container, 1).is_empty());
+        // both hours are in the same 6 h bucket
+        assert_eq!(starts(&container, 2), vec![T]);
+        assert_eq!(container.tiers[2].buckets[0].1.count, 3);
+
+        // 6 h buckets are kept for 30 days
+        container.downsample(T + 6 * 3600 + 30 * 24 * 3600 - 1);
+        assert_eq!(starts(&container, 2), vec![T]);
+        container.downsample(T + 6 * 3600 + 30 * 24 * 3600);
+        assert!(starts(&container, 2).is_empty());
+    }
+
+    #[test]
+    fn history_has_gaps() {
+        let mut container = HistoryContainer::new(25, Aggregation::Mean, None);
+        let now = T + 10 * 3600 + 5;
+        container.insert_at(1.0, at(now - 3 * 3600));
+        container.insert_at(2.0, at(now - 3 * 3600 + 60));
+        container.insert_at(5.0, at(now));
+        let history = container.get_history_at(HistoryRange::Day, at(now));
+        assert_eq!(history.len(), 25);
+        for (index, value) in history.iter().enumerate() {
+            match index {
+                21 => assert_eq!(value.map(|value| value.value), Some(1.5)),
+                24 => assert_eq!(value.map(|value| value.value), Some(5.0)),
+                _ => assert_eq!(*value, None, "interval {} should be empty", index),
+            }
+        }
+    }
+
+    #[test]
+    fn history_combines_tiers_and_samples() {
+        let mut container = HistoryContainer::new(8, Aggregation::MinMax, None);
+        let now = T + 14 * 24 * 3600;
+        // in the 6 h, 1 h, 5 min tiers and raw samples
+        for (age, value) in [(10 * 24 * 3600, 1.0), (3 * 24 * 3600, 2.0), (5 * 3600, 3.0), (60, 4.0)] {
+            container.insert_at(value, at(now - age));
+        }
+        container.downsample(now);
+        assert_eq!(container.samples.len(), 1);
+        assert!(container.tiers.iter().all(|tier| tier.buckets.len() == 1));
+        let values: Vec<f32> = container.get_history_at(HistoryRange::Month, at(now)).iter()
+            .flatten().map(|value| value.value).collect();
+        assert_eq!(values, vec![1.0, 2.0, 3.5]);
+    }
+
+    #[test]
+    fn restore_round_trip() {
+        let dir = std::env::temp_dir().join(format!("history_test_{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().into_owned();
+        let now = T + 2 * 24 * 3600;
+        let mut container = HistoryContainer::open_at(25, Aggregation::MinMax,
+                                                      HistoryStorage::open(&dir_name, "t"), at(T));
+        // every 10 min for 2 days, the file is rewritten several times
+        for index in 0..=2 * 24 * 6 {
+            container.insert_at(index as f32 % 7.0, at(T + index * 600));
+        }
+        let restored = HistoryContainer::open_at(25, Aggregation::MinMax, HistoryStorage::open(&dir_name, "t"),
+                                                 at(now));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(restored.samples, container.samples);
+        for tier in 0..TIERS.len() {
+            assert_eq!(starts(&restored, tier), starts(&container, tier));
+        }
+        for range in [HistoryRange::Day, HistoryRange::Week, HistoryRange::Month] {
+            assert_eq!(restored.get_history_at(range, at(now)), container.get_history_at(range, at(now)));
+        }
+    }
 
-        // copy the most old elements
-        let rest_length = last_index - self.current_time_index;
-        if rest_length > 0 {
-            history[0..rest_length].copy_from_slice(&self.data[(self.current_time_index + 1)..]);
+    #[test]
+    fn restore_with_clock_behind() {
+        let dir = std::env::temp_dir().join(format!("history_clock_test_{}", std::process::id()));
+        let dir_name = dir.to_string_lossy().into_owned();
+        let mut container = HistoryContainer::open_at(25, Aggregation::Mean, HistoryStorage::open(&dir_name, "t"),
+                                                      at(T));
+        for index in 0..30 {
+            container.insert_at(index as f32, at(T + index * 60));
         }
-        // copy the most recent elements
-        history[rest_length..(rest_length + self.current_time_index)].copy_from_slice(&self.data[1..=self.current_time_index]);
-        history
+        // e.g. booted without RTC, the clock isn't synchronized yet
+        let early = HistoryContainer::open_at(25, Aggregation::Mean, HistoryStorage::open(&dir_name, "t"),
+                                              at(T - 24 * 3600));
+        assert_eq!(early.samples, container.samples);
+        let now = T + 30 * 60;
+        let restored = HistoryContainer::open_at(25, Aggregation::Mean, HistoryStorage::open(&dir_name, "t"),
+                                                 at(now));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(restored.samples, container.samples);
+        assert_eq!(restored.get_history_at(HistoryRange::Day, at(now)),
+                   container.get_history_at(HistoryRange::Day, at(now)));
     }
 }
@@ -0,0 +1,114 @@
+use json::JsonValue;
+use serde::Deserialize;
+
+
+// Format of MQTT payloads of one topic. Every format is decoded to JSON value, so the data sources
+// locate the values in the same way:
+//  json       - as is
+//  scalar     - "23.4" becomes number 23.4, any other text becomes string
+//  csv        - "23.4,45" becomes [23.4, 45], several lines become array of arrays
+//  key_value  - "t=23.4 rh=45" becomes {"t": 23.4, "rh": 45}, pairs are separated by spaces, commas,
+//               semicolons or new lines
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayloadFormat {
+    #[default]
+    Json,
+    Scalar,
+    Csv,
+    KeyValue
+}
+
+impl PayloadFormat {
+    pub fn decode(&self, payload: &str) -> Result<JsonValue, String> {
+        match self {
+            PayloadFormat::Json => json::parse(payload).map_err(|e| e.to_string()),
+            PayloadFormat::Scalar => Ok(decode_scalar(payload)),
+            PayloadFormat::Csv => decode_csv(payload),
+            PayloadFormat::KeyValue => decode_key_value(payload),
+        }
+    }
+}
+
+fn decode_scalar(text: &str) -> JsonValue {
+    let text = text.trim();
+    match text.parse::<f64>() {
+        Ok(number) if number.is_finite() => number.into(),
+        _ => text.into(),
+    }
+}
+
+fn decode_csv(payload: &str) -> Result<JsonValue, String> {
+    let mut rows: Vec<JsonValue> = payload.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| JsonValue::Array(line.split([',', ';']).map(decode_scalar).collect()))
+        .collect();
+    match rows.len() {
+        0 => Err("empty CSV payload".to_owned()),
+        1 => Ok(rows.remove(0)),
+        _ => Ok(JsonValue::Array(rows)),
+    }
+}
+
+fn decode_key_value(payload: &str) -> Result<JsonValue, String> {
+    let mut object = JsonValue::new_object();
+    for pair in payload.split(|c: char| c.is_whitespace() || c == ',' || c == ';').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() => object[key] = decode_scalar(value),
+            _ => return Err(format!("invalid key=value pair {:?}", pair)),
+        }
+    }
+    if object.is_empty() {
+        return Err("empty key=value payload".to_owned());
+    }
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        assert_eq!(PayloadFormat::Json.decode(r#"{"t": 21.5}"#).unwrap()["t"], 21.5);
+        assert!(PayloadFormat::Json.decode("online").is_err());
+    }
+
+    #[test]
+    fn scalar() {
+        assert_eq!(PayloadFormat::Scalar.decode(" 23.4\n").unwrap(), 23.4);
+        assert_eq!(PayloadFormat::Scalar.decode("-5").unwrap(), -5);
+        assert_eq!(PayloadFormat::Scalar.decode("online").unwrap(), "online");
+        // not finite numbers aren't valid JSON
+        assert_eq!(PayloadFormat::Scalar.decode("NaN").unwrap(), "NaN");
+        assert_eq!(PayloadFormat::Scalar.decode("inf").unwrap(), "inf");
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(PayloadFormat::Csv.decode("23.4,45;800").unwrap(), json::array![23.4, 45, 800]);
+        assert_eq!(PayloadFormat::Csv.decode(" 23.4 , ok ").unwrap(), json::array![23.4, "ok"]);
+        assert_eq!(PayloadFormat::Csv.decode("1,2\n\n3,4\n").unwrap(), json::array![[1, 2], [3, 4]]);
+        assert!(PayloadFormat::Csv.decode("").is_err());
+        assert!(PayloadFormat::Csv.decode(" \n").is_err());
+    }
+
+    #[test]
+    fn key_value() {
+        let decoded = PayloadFormat::KeyValue.decode("t=23.4 rh=45,co2=800;state=ok\nempty=").unwrap();
+        assert_eq!(decoded["t"], 23.4);
+        assert_eq!(decoded["rh"], 45);
+        assert_eq!(decoded["co2"], 800);
+        assert_eq!(decoded["state"], "ok");
+        assert_eq!(decoded["empty"], "");
+        // only the first "=" separates the value
+        assert_eq!(PayloadFormat::KeyValue.decode("url=a=b").unwrap()["url"], "a=b");
+    }
+
+    #[test]
+    fn invalid_key_value() {
+        for payload in ["", " ,; ", "t", "=5", "t=1 rh"] {
+            assert!(PayloadFormat::KeyValue.decode(payload).is_err(), "{:?} should be invalid", payload);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use json::JsonValue;
+use crate::decoder::PayloadFormat;
+use crate::selector::Selector;
+use crate::sources::{FieldMapping, Target, Unit};
+
+// Sensor announced by Home Assistant MQTT discovery
+#[derive(Debug)]
+pub struct DiscoveredSensor {
+    pub state_topic: String,
+    pub format: PayloadFormat,
+    pub field: FieldMapping,
+    // `expire_after` of the sensor
+    pub max_age_s: Option<u64>
+}
+
+// Config topics of sensors with and without node id
+pub fn discovery_topics(prefix: &str) -> Vec<String> {
+    vec![prefix.to_owned() + "/sensor/+/config", prefix.to_owned() + "/sensor/+/+/config"]
+}
+
+pub fn is_discovery_topic(prefix: &str, topic: &str) -> bool {
+    discovery_topics(prefix).iter().any(|filter| rumqttc::matches(topic, filter))
+}
+
+// Returns None for sensors which aren't shown, i.e. the device class isn't temperature, humidity or CO2
+pub fn parse_config(config: &JsonValue) -> Result<Option<DiscoveredSensor>, String> {
+    let target = match get(config, "device_class", "dev_cla").as_str() {
+        Some("temperature") => Target::IndoorT,
+        Some("humidity") => Target::IndoorRh,
+        Some("carbon_dioxide") => Target::IndoorCo2,
+        _ => return Ok(None),
+    };
+    let state_topic = get(config, "state_topic", "stat_t").as_str().ok_or("no state_topic")?;
+    // topics can be abbreviated with the base topic "~"
+    let state_topic = match config["~"].as_str() {
+        Some(base) => state_topic.replace('~', base),
+        None => state_topic.to_owned(),
+    };
+
+    let (format, path, scale) = match get(config, "value_template", "val_tpl").as_str() {
+        Some(template) => parse_value_template(template)?,
+        None => (PayloadFormat::Scalar, "$".to_owned(), 1.0),
+    };
+    let unit = match (target, get(config, "unit_of_measurement", "unit_of_meas").as_str()) {
+        (Target::IndoorT, Some(unit)) => Some(unit.parse::<Unit>()?),
+        _ => None,
+    };
+
+    Ok(Some(DiscoveredSensor {
+        state_topic,
+        format,
+        field: FieldMapping { path: Selector::parse(&path)?, target, scale, offset: 0.0, unit },
+        max_age_s: get(config, "expire_after", "exp_aft").as_u64()
+    }))
+}
+
+fn get<'a>(config: &'a JsonValue, key: &str, abbreviation: &str) -> &'a JsonValue {
+    match &config[key] {
+        JsonValue::Null => &config[abbreviation],
+        value => value,
+    }
+}
+
+// Supported subset of templates: "{{ value }}" and "{{ value_json<path> }}" with filters
+// `float`, `int`, `round(n)` and `multiply(x)`, e.g. "{{ value_json.SI7021.Temperature | float | round(1) }}".
+// Returns payload format, path of the value and its scale.
+fn parse_value_template(template: &str) -> Result<(PayloadFormat, String, f32), String> {
+    let unsupported = || format!("unsupported value_template {:?}", template);
+    let expression = template.trim()
+        .strip_prefix("{{").and_then(|rest| rest.strip_suffix("}}"))
+        .ok_or_else(unsupported)?;
+    let mut parts = expression.split('|').map(|part| part.trim());
+    let value = parts.next().unwrap_or_default();
+    let (format, path) = match value.strip_prefix("value_json") {
+        Some(path) => (PayloadFormat::Json, "$".to_owned() + path),
+        None if value == "value" => (PayloadFormat::Scalar, "$".to_owned()),
+        None => return Err(unsupported()),
+    };
+
+    let mut scale = 1.0;
+    for filter in parts {
+        let (name, argument) = match filter.split_once('(') {
+            Some((name, argument)) => (name.trim(), argument.strip_suffix(')').ok_or_else(unsupported)?.trim()),
+            None => (filter, ""),
+        };
+        match name {
+            // rounding is done by the display precision
+            "float" | "int" | "round" => {},
+            "multiply" => scale *= argument.parse::<f32>().map_err(|_| unsupported())?,
+            _ => return Err(unsupported()),
+        }
+    }
+    Ok((format, path, scale))
+}
@@ -1,16 +1,38 @@
-use std::sync::{Arc, RwLock};
+use std::rc::Rc;
+use std::thread;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
 use envconfig::Envconfig;
 use slint::*;
 use ui::*;
-use model::Model;
-use connector::MQTTConnector;
-use database::DataBase;
+use model::{Model, DataSource, Metric};
+use connector::{MQTTConnector, Secret};
+use database::{DataBase, Aggregation};
+use decoder::PayloadFormat;
+use status::StatusReporter;
+use commands::CommandHandler;
+use backlight::Backlight;
+use snapshot::{SnapshotFormat, Snapshots};
+use recorder::RecordingConfig;
+use motion::{MotionConfig, Roi};
+use video::{VideoOverlays, VideoSource, VideoSources, VideoStats};
 
 pub mod ui;
 pub mod model;
 pub mod connector;
 pub mod video;
 pub mod database;
+pub mod storage;
+pub mod sources;
+pub mod selector;
+pub mod decoder;
+pub mod discovery;
+pub mod status;
+pub mod commands;
+pub mod backlight;
+pub mod snapshot;
+pub mod recorder;
+pub mod motion;
 
 #[derive(Debug)]
 #[derive(Envconfig)]
@@ -24,12 +46,59 @@ pub struct Config {
     #[envconfig(from = "MQTT_BROKER_KEEP_ALIVE", default = "5")]
     pub mqtt_keep_alive: u16,
 
+    #[envconfig(from = "MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+
+    #[envconfig(from = "MQTT_PASSWORD")]
+    pub mqtt_password: Option<Secret>,
+
+    #[envconfig(from = "MQTT_PASSWORD_FILE")]
+    pub mqtt_password_file: Option<String>,
+
+    #[envconfig(from = "MQTT_TLS", default = "false")]
+    pub mqtt_tls: bool,
+
+    #[envconfig(from = "MQTT_CA_CERT")]
+    pub mqtt_ca_cert: Option<String>,
+
+    #[envconfig(from = "MQTT_CLIENT_CERT")]
+    pub mqtt_client_cert: Option<String>,
+
+    #[envconfig(from = "MQTT_CLIENT_KEY")]
+    pub mqtt_client_key: Option<String>,
+
     #[envconfig(from = "MQTT_BROKER_BASE_TOPIC", default = "homeassistant/sensor")]
     pub mqtt_base_topic: String,
 
     #[envconfig(from = "MQTT_CONTROLLER_NAME", default = "cubieboard")]
     pub mqtt_controller_name: String,
 
+    // display publishes "<topic>/availability" (online/offline) and "<topic>/telemetry"
+    #[envconfig(from = "MQTT_DISPLAY_TOPIC", default = "meteo_display")]
+    pub mqtt_display_topic: String,
+
+    #[envconfig(from = "TELEMETRY_INTERVAL_S", default = "60")]
+    pub telemetry_interval_s: u64,
+
+    // sysfs backlight for brightness and blank commands, e.g. /sys/class/backlight/backlight
+    #[envconfig(from = "BACKLIGHT_PATH", default = "")]
+    pub backlight_path: String,
+
+    // TOML file with data sources, the built-in 'Weather Provider' topics are used if it's empty
+    #[envconfig(from = "DATA_SOURCES_FILE", default = "")]
+    pub data_sources_file: String,
+
+    // Home Assistant MQTT discovery of temperature, humidity and CO2 sensors
+    #[envconfig(from = "HA_DISCOVERY", default = "false")]
+    pub ha_discovery: bool,
+
+    #[envconfig(from = "HA_DISCOVERY_PREFIX", default = "homeassistant")]
+    pub ha_discovery_prefix: String,
+
+    // only sensors with config topic containing it are used, e.g. the node id
+    #[envconfig(from = "HA_DISCOVERY_FILTER", default = "")]
+    pub ha_discovery_filter: String,
+
     #[envconfig(from = "DEFAULT_TIMEZONE_OFFSET_H", default = "3")]
     pub timezone_offset_h: i8,
 
@@ -39,64 +108,368 @@ pub struct Config {
     #[envconfig(from = "RB_MIN_PROBABILITY_THRH_PRCNT", default = "50")]
     pub rb_min_prob_thrh: u8,
 
+    #[envconfig(from = "HTU21D_MAX_AGE_S", default = "300")]
+    pub htu21d_max_age_s: u64,
+
+    #[envconfig(from = "MHZ19_MAX_AGE_S", default = "300")]
+    pub mhz19_max_age_s: u64,
+
+    #[envconfig(from = "NOAA_KP_MAX_AGE_S", default = "10800")]
+    pub noaa_kp_max_age_s: u64,
+
+    #[envconfig(from = "NOAA_KP_INST_MAX_AGE_S", default = "3600")]
+    pub noaa_kp_inst_max_age_s: u64,
+
+    #[envconfig(from = "NOAA_FLUX_MAX_AGE_S", default = "3600")]
+    pub noaa_flux_max_age_s: u64,
+
+    #[envconfig(from = "NOAA_SW_FORECAST_MAX_AGE_S", default = "86400")]
+    pub noaa_sw_forecast_max_age_s: u64,
+
     #[envconfig(from = "VIDEO_URL",
         default = "https://zetcast-gb.b-cdn.net/cf_burradale/BurradaleABR/burradale/stream_720p/chunks.m3u8")]
     pub video_url: String,
 
+    // "name=uri;name=uri", VIDEO_URL is used if it's empty
+    #[envconfig(from = "VIDEO_SOURCES", default = "")]
+    pub video_sources: VideoSources,
+
+    // time to show each of VIDEO_SOURCES, 0 - switch manually only
+    #[envconfig(from = "VIDEO_ROTATION_S", default = "0")]
+    pub video_rotation_s: u64,
+
+    // any of "time,source,stats,badge"
+    #[envconfig(from = "VIDEO_OVERLAYS", default = "time,badge")]
+    pub video_overlays: VideoOverlays,
+
     #[envconfig(from = "VIDEO_MAX_RATE", default = "10")]
     pub video_max_rate: u8,
 
+    // pipeline is restarted when no frames come for this time
+    #[envconfig(from = "VIDEO_STALL_TIMEOUT_S", default = "15")]
+    pub video_stall_timeout_s: u64,
+
+    // segments of the video stream, empty value disables recording
+    #[envconfig(from = "RECORDING_DIR", default = "")]
+    pub recording_dir: String,
+
+    #[envconfig(from = "RECORDING_SEGMENT_S", default = "300")]
+    pub recording_segment_s: u64,
+
+    // retention limits, 0 - no limit
+    #[envconfig(from = "RECORDING_MAX_SIZE_MB", default = "1024")]
+    pub recording_max_size_mb: u64,
+
+    #[envconfig(from = "RECORDING_MAX_AGE_H", default = "24")]
+    pub recording_max_age_h: u64,
+
+    #[envconfig(from = "RECORDING_AUTOSTART", default = "false")]
+    pub recording_autostart: bool,
+
+    // frame difference detector on the shown video
+    #[envconfig(from = "MOTION_DETECTION", default = "false")]
+    pub motion_detection: bool,
+
+    // "x,y,width,height" in fractions of the frame
+    #[envconfig(from = "MOTION_ROI", default = "0,0,1,1")]
+    pub motion_roi: Roi,
+
+    // min change of pixel brightness 0..255
+    #[envconfig(from = "MOTION_PIXEL_THRH", default = "25")]
+    pub motion_pixel_thrh: u8,
+
+    // min share of changed pixels of the region
+    #[envconfig(from = "MOTION_MIN_AREA_PRCNT", default = "2")]
+    pub motion_min_area_prcnt: f32,
+
+    #[envconfig(from = "MOTION_HOLD_S", default = "5")]
+    pub motion_hold_s: u64,
+
+    #[envconfig(from = "MOTION_WAKE", default = "false")]
+    pub motion_wake: bool,
+
+    // stills of the video, empty value disables them
+    #[envconfig(from = "SNAPSHOT_DIR", default = "snapshots")]
+    pub snapshot_dir: String,
+
+    #[envconfig(from = "SNAPSHOT_FORMAT", default = "jpeg")]
+    pub snapshot_format: SnapshotFormat,
+
+    // 0 - on demand only
+    #[envconfig(from = "SNAPSHOT_INTERVAL_S", default = "0")]
+    pub snapshot_interval_s: u64,
+
     #[envconfig(from = "HISTORY_N_ELEMENTS", default = "25")]
     pub history_n_elements: usize,
 
-    #[envconfig(from = "HISTORY_INTERVAL_S", default = "3600")]
-    pub history_interval_s: u64
+    #[envconfig(from = "HISTORY_DATA_DIR", default = "data")]
+    pub history_data_dir: String,
+
+    #[envconfig(from = "INDOOR_T_PRECISION", default = "1")]
+    pub indoor_t_precision: u8,
+
+    #[envconfig(from = "INDOOR_RH_PRECISION", default = "0")]
+    pub indoor_rh_precision: u8,
+
+    #[envconfig(from = "INDOOR_CO2_PRECISION", default = "0")]
+    pub indoor_co2_precision: u8,
+
+    #[envconfig(from = "INDOOR_T_AGGREGATION", default = "minmax")]
+    pub indoor_t_aggregation: Aggregation,
+
+    #[envconfig(from = "INDOOR_RH_AGGREGATION", default = "mean")]
+    pub indoor_rh_aggregation: Aggregation,
+
+    #[envconfig(from = "INDOOR_CO2_AGGREGATION", default = "max")]
+    pub indoor_co2_aggregation: Aggregation
 }
 
 fn main() {
     let config = Config::init_from_env().unwrap();
     println!("Using config:\n{:?}", config);
+    if std::env::var_os("HISTORY_INTERVAL_S").is_some() {
+        println!("HISTORY_INTERVAL_S is ignored, history is kept with 5 min, 1 h and 6 h intervals for 24H, 7D and 30D charts");
+    }
     let config_ref = Arc::new(config);
 
     // UI
     let ui = AppWindow::new().unwrap();
     let window_updater = WindowUpdater::new(ui.as_weak());
+    window_updater.update_indoor_precision(config_ref.indoor_t_precision.into(),
+                                           config_ref.indoor_rh_precision.into(),
+                                           config_ref.indoor_co2_precision.into());
 
     let db = DataBase::new(config_ref.clone());
     let mut meteo_model = Model::new(config_ref.clone(), db);
 
     // data_view_map
-    meteo_model.add_map(vec![
-        ("htu21d", Model::indoor_t_rh_callback),
-        ("mhz19", Model::indoor_co2_callback),
-        ("noaa_kp",Model::space_weather_kp_callback),
-        ("noaa_kp_inst", Model::space_weather_kp_inst_callback),
-        ("noaa_flux", Model::space_weather_flux_callback),
-        ("noaa_sw_forecast", Model::space_weather_forecast_callback)
-    ]);
+    if !config_ref.data_sources_file.is_empty() {
+        meteo_model.add_sources(sources::load_sources(&config_ref.data_sources_file).unwrap());
+    } else {
+        // indoor values come from the discovered sensors instead of the built-in ones
+        if !config_ref.ha_discovery {
+            meteo_model.add_map(vec![
+                ("htu21d", DataSource::new(Model::indoor_t_rh_callback, vec![Metric::IndoorT, Metric::IndoorRh],
+                                           config_ref.htu21d_max_age_s)),
+                ("mhz19", DataSource::new(Model::indoor_co2_callback, vec![Metric::IndoorCo2],
+                                          config_ref.mhz19_max_age_s))
+            ]);
+        }
+        meteo_model.add_map(vec![
+            ("noaa_kp", DataSource::new(Model::space_weather_kp_callback, vec![Metric::KpHistory],
+                                        config_ref.noaa_kp_max_age_s)),
+            ("noaa_kp_inst", DataSource::new(Model::space_weather_kp_inst_callback, vec![Metric::Kp],
+                                             config_ref.noaa_kp_inst_max_age_s)),
+            ("noaa_flux", DataSource::new(Model::space_weather_flux_callback, vec![Metric::SolarRadiation],
+                                          config_ref.noaa_flux_max_age_s)),
+            ("noaa_sw_forecast", DataSource::new(Model::space_weather_forecast_callback,
+                                                 vec![Metric::KpForecast, Metric::SolarRadiationForecast],
+                                                 config_ref.noaa_sw_forecast_max_age_s))
+        ]);
+    }
 
     // Connector
     let model_ref_lock = Arc::new(RwLock::new(meteo_model));
 
     let model_ref_lock2 = model_ref_lock.clone();
     let on_notify_cb = move |topic, payload| {
-        model_ref_lock2.write().expect("Couldn't get write access to model - still locked")
+        // the model stays usable after a panic in one of the data callbacks
+        model_ref_lock2.write().unwrap_or_else(PoisonError::into_inner)
             .on_notification(window_updater.clone(), topic, payload);
     };
-    let mut mqtt_connector = MQTTConnector::new("display", config_ref.clone(), on_notify_cb).unwrap();
+    let state_updater = WindowUpdater::new(ui.as_weak());
+    let timezone_offset_h = config_ref.timezone_offset_h;
+    let on_state_cb = move |state| {
+        state_updater.update_mqtt_state(state, timezone_offset_h);
+    };
+    let mut mqtt_connector = MQTTConnector::new("display", config_ref.clone(), on_notify_cb, on_state_cb).unwrap();
+
+    let model_ref_lock3 = model_ref_lock.clone();
+    let range_updater = WindowUpdater::new(ui.as_weak());
+    ui.global::<IndoorAdapter>().on_cycle_history_range(move || {
+        model_ref_lock3.write().unwrap_or_else(PoisonError::into_inner)
+            .cycle_history_range(range_updater.clone());
+    });
+
+    // Stale data check, it works also when no MQTT data is received
+    let model_ref_lock4 = model_ref_lock.clone();
+    let freshness_updater = WindowUpdater::new(ui.as_weak());
+    let freshness_timer = Timer::default();
+    freshness_timer.start(TimerMode::Repeated, Duration::from_secs(10), move || {
+        model_ref_lock4.write().unwrap_or_else(PoisonError::into_inner)
+            .check_freshness(freshness_updater.clone());
+    });
 
-    for topic in model_ref_lock.read().expect("Couldn't get read access to model - still locked")
-        .data_view_map.keys() {
-            mqtt_connector.subscribe_client(topic);
+    model_ref_lock.write().unwrap_or_else(PoisonError::into_inner).subscriber = Some(mqtt_connector.subscriber());
+    for (topic, source) in model_ref_lock.read().unwrap_or_else(PoisonError::into_inner)
+        .data_view_map.iter() {
+            mqtt_connector.subscribe_client(topic, source.format);
+    }
+    if config_ref.ha_discovery {
+        for topic in discovery::discovery_topics(&config_ref.ha_discovery_prefix) {
+            mqtt_connector.subscribe_client(&topic, PayloadFormat::Json);
+        }
     }
 
     // Video
-    // TODO: get video_frame_width from slint
-    let video_frame_width = 540;
-    video::init_pipeline(&config_ref.clone().video_url,
-                        video_frame_width,
-                        config_ref.clone().video_max_rate,
-                        WindowUpdater::new(ui.as_weak()));
+    let video_stats = VideoStats::default();
+    let mut video_sources = config_ref.video_sources.0.clone();
+    if video_sources.is_empty() {
+        video_sources.push(VideoSource { name: String::new(), uri: config_ref.video_url.clone() });
+    }
+    WindowUpdater::new(ui.as_weak()).update_video_source_count(video_sources.len());
+    let recording = (!config_ref.recording_dir.is_empty()).then(|| RecordingConfig {
+        dir: config_ref.recording_dir.clone().into(),
+        segment: Duration::from_secs(config_ref.recording_segment_s.max(1)),
+        max_bytes: config_ref.recording_max_size_mb * 1024 * 1024,
+        max_age: Duration::from_secs(config_ref.recording_max_age_h * 3600),
+        timezone_offset_h: config_ref.timezone_offset_h
+    });
+    WindowUpdater::new(ui.as_weak()).update_video_recording_enabled(recording.is_some());
+    let video_player = video::init_pipeline(video_sources,
+                                        video::DEFAULT_FRAME_SIZE,
+                                        config_ref.clone().video_max_rate,
+                                        Duration::from_secs(config_ref.video_stall_timeout_s),
+                                        recording,
+                                        WindowUpdater::new(ui.as_weak()),
+                                        video_stats.clone());
+
+    let toggle_player = video_player.clone();
+    ui.global::<VideoAdapter>().on_toggle_pause_play(move || {
+        // the UI thread doesn't wait for a restart of the pipeline
+        toggle_player.control(|video| {
+            if let Err(e) = video.toggle_pause_play() {
+                println!("Couldn't pause/resume video: {:?}", e);
+            }
+        });
+    });
+
+    if config_ref.recording_autostart {
+        if let Err(e) = video_player.set_recording(true) {
+            println!("Couldn't start recording: {:?}", e);
+        }
+    }
+    let recording_player = video_player.clone();
+    ui.global::<VideoAdapter>().on_toggle_recording(move || {
+        recording_player.control(|video| {
+            if let Err(e) = video.toggle_recording() {
+                println!("Couldn't start/stop recording: {:?}", e);
+            }
+        });
+    });
+
+    if config_ref.motion_detection {
+        let motion = MotionConfig {
+            roi: config_ref.motion_roi,
+            pixel_threshold: config_ref.motion_pixel_thrh,
+            min_area_prcnt: config_ref.motion_min_area_prcnt,
+            hold: Duration::from_secs(config_ref.motion_hold_s)
+        };
+        video_player.set_frame_observer(motion::frame_observer(&config_ref, motion, WindowUpdater::new(ui.as_weak()),
+                                                               mqtt_connector.publisher()));
+    }
+
+    // Overlays, the clock is updated by the UI timer as the pipeline can be restarting
+    let overlay_updater = WindowUpdater::new(ui.as_weak());
+    overlay_updater.update_video_overlays(config_ref.video_overlays);
+    let clock_timer = Timer::default();
+    if config_ref.video_overlays.time {
+        // the same time zone as the rest of the data, the system one can be UTC
+        let timezone_offset = chrono::Duration::hours(config_ref.timezone_offset_h.into());
+        clock_timer.start(TimerMode::Repeated, Duration::from_secs(1), move || {
+            let now = chrono::Utc::now() + timezone_offset;
+            overlay_updater.update_video_time(now.format("%H:%M:%S").to_string());
+        });
+    }
+
+    // frames are scaled by the pipeline to the size of the widget
+    let size_player = video_player.clone();
+    let ui_weak = ui.as_weak();
+    let frame_size_timer = Timer::default();
+    frame_size_timer.start(TimerMode::Repeated, Duration::from_secs(1), move || {
+        if let Some(ui) = ui_weak.upgrade() {
+            let scale = ui.window().scale_factor();
+            size_player.set_frame_size((ui.get_video_frame_width() * scale) as u32,
+                                       (ui.get_video_frame_height() * scale) as u32);
+        }
+    });
+
+    // the rotation starts over after a manual switch
+    let rotation_timer = Rc::new(Timer::default());
+    if config_ref.video_rotation_s > 0 {
+        let rotation_player = video_player.clone();
+        rotation_timer.start(TimerMode::Repeated, Duration::from_secs(config_ref.video_rotation_s), move || {
+            // the pipeline is restarted on the video control thread
+            rotation_player.control(|video| {
+                if video.is_playing() {
+                    if let Err(e) = video.switch_source(1) {
+                        println!("Couldn't switch video source: {:?}", e);
+                    }
+                }
+            });
+        });
+    }
+    let switch_player = video_player.clone();
+    let switch_timer = rotation_timer.clone();
+    ui.global::<VideoAdapter>().on_switch_source(move |step| {
+        switch_player.control(move |video| {
+            if let Err(e) = video.switch_source(step as isize) {
+                println!("Couldn't switch video source: {:?}", e);
+            }
+        });
+        switch_timer.restart();
+    });
+
+    // Snapshots, they are encoded out of the UI thread
+    let snapshots = Snapshots::open(&config_ref.snapshot_dir, config_ref.snapshot_format,
+                                    config_ref.timezone_offset_h, video_player.clone());
+    let snapshot_ui = snapshots.clone();
+    let snapshot_updater = WindowUpdater::new(ui.as_weak());
+    ui.global::<VideoAdapter>().on_take_snapshot(move || {
+        let snapshots = snapshot_ui.clone();
+        let updater = snapshot_updater.clone();
+        thread::spawn(move || {
+            let message = match snapshots.map(|snapshots| snapshots.take()) {
+                Some(Ok(path)) => std::format!("Snapshot saved to {}", path.display()),
+                Some(Err(e)) => std::format!("Snapshot failed: {}", e),
+                None => "Snapshots are disabled".to_owned(),
+            };
+            updater.show_message(message, Duration::from_secs(3));
+        });
+    });
+    if let Some(snapshots) = snapshots.clone().filter(|_| config_ref.snapshot_interval_s > 0) {
+        let interval = Duration::from_secs(config_ref.snapshot_interval_s);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = snapshots.take() {
+                println!("Snapshot: {:?}", e);
+            }
+        });
+    }
+
+    // Remote control
+    model_ref_lock.write().unwrap_or_else(PoisonError::into_inner).commands =
+        Some(CommandHandler::new(&config_ref, video_player.clone(), mqtt_connector.publisher(),
+                                 Backlight::open(&config_ref.backlight_path), snapshots));
+    mqtt_connector.subscribe_client(&commands::command_topic(&config_ref), PayloadFormat::Json);
+    let wake_backlight = Backlight::open(&config_ref.backlight_path);
+    ui.global::<DisplayAdapter>().on_wake(move || {
+        if let Some(backlight) = &wake_backlight {
+            if let Err(e) = backlight.set_power(true) {
+                println!("Couldn't turn on backlight: {:?}", e);
+            }
+        }
+    });
+
+    // Telemetry
+    let model_ref_lock5 = model_ref_lock.clone();
+    let mut status_reporter = StatusReporter::new(&config_ref, mqtt_connector.publisher(),
+                                                  mqtt_connector.payload_errors(), video_player.clone(),
+                                                  video_stats);
+    let telemetry_timer = Timer::default();
+    telemetry_timer.start(TimerMode::Repeated, Duration::from_secs(config_ref.telemetry_interval_s.max(1)), move || {
+        status_reporter.publish_telemetry(&model_ref_lock5.read().unwrap_or_else(PoisonError::into_inner));
+    });
 
     ui.run().unwrap();
 }
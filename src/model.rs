@@ -1,36 +1,153 @@
 use json::*;
 use std::sync::Arc;
+use std::time::Duration;
 use chrono::{DateTime, NaiveDateTime, Timelike, Utc};
-use crate::ui::{WindowUpdater, KpIndexUI};
+use crate::ui::{WindowUpdater, KpIndexUI, DataStateUI};
 use crate::Config;
 use std::collections::HashMap;
-use crate::database::DataBase;
+use crate::database::{DataBase, HistoryRange};
+use crate::sources::{FieldMapping, SourceConfig, Target, DEFAULT_MAX_AGE_S};
+use crate::decoder::PayloadFormat;
+use crate::connector::MQTTSubscriber;
+use crate::discovery;
+use crate::commands::CommandHandler;
 
 pub type DataViewMapKeyType = String;
-pub type DataViewMapValueType = fn(&mut Model, WindowUpdater, JsonValue) -> ();
+pub type DataViewMapValueType = DataSource;
 pub type DataViewMap = HashMap<DataViewMapKeyType, DataViewMapValueType>;
+pub type DataCallback = fn(&mut Model, WindowUpdater, JsonValue) -> ();
 
+// How the payload is turned into displayed values
+#[derive(Clone)]
+pub enum DataParser {
+    Callback(DataCallback),
+    Fields(Vec<FieldMapping>)
+}
+
+// Source of data published to one MQTT topic
+pub struct DataSource {
+    pub parser: DataParser,
+    pub format: PayloadFormat,
+    // metrics fed by the source, they become stale when nothing is received during `max_age`
+    pub metrics: Vec<Metric>,
+    pub max_age: Duration,
+    pub last_received: Option<DateTime<Utc>>
+}
+
+impl DataSource {
+    pub fn new(callback: DataCallback, metrics: Vec<Metric>, max_age_s: u64) -> Self {
+        Self { parser: DataParser::Callback(callback), format: PayloadFormat::Json, metrics,
+               max_age: Duration::from_secs(max_age_s), last_received: None }
+    }
+    pub fn from_fields(fields: Vec<FieldMapping>, max_age_s: u64) -> Self {
+        let mut metrics: Vec<Metric> = Vec::new();
+        for metric in fields.iter().map(|field| field.target.metric()) {
+            if !metrics.contains(&metric) {
+                metrics.push(metric);
+            }
+        }
+        Self { parser: DataParser::Fields(fields), format: PayloadFormat::Json, metrics,
+               max_age: Duration::from_secs(max_age_s), last_received: None }
+    }
+}
+
+// Displayed values which can be missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    IndoorT,
+    IndoorRh,
+    IndoorCo2,
+    KpHistory,
+    Kp,
+    KpForecast,
+    SolarRadiation,
+    SolarRadiationForecast
+}
 
 pub struct Model {
     pub config: Arc<Config>,
     pub data_view_map: DataViewMap,
-    pub db: DataBase
+    pub db: DataBase,
+    pub history_range: HistoryRange,
+    pub metric_states: HashMap<Metric, DataStateUI>,
+    // for topics found by Home Assistant discovery
+    pub subscriber: Option<MQTTSubscriber>,
+    // config topic -> state topic of the shown discovered sensors
+    pub discovered: HashMap<String, String>,
+    pub commands: Option<CommandHandler>
 }
 
 impl Model {
     pub fn new(config: Arc<Config>, db: DataBase) -> Self {
-        Model {config: config.clone(), data_view_map: DataViewMap::new(), db }
+        Model {config: config.clone(), data_view_map: DataViewMap::new(), db, history_range: HistoryRange::Day,
+               metric_states: HashMap::new(), subscriber: None, discovered: HashMap::new(),
+               commands: None }
     }
 
     pub fn on_notification(&mut self, updater: WindowUpdater, topic: String, payload: json::JsonValue) {
-        if self.data_view_map.contains_key(topic.as_str()) {
-            // println!("json_payload = {:?}", payload);
-            match self.data_view_map.get(topic.as_str()) {
-                Some(callback) => {
-                    callback(self, updater, payload);
+        // println!("json_payload = {:?}", payload);
+        if payload.is_null() {
+            // an empty retained message, only discovery uses it to remove a sensor
+            if self.config.ha_discovery && discovery::is_discovery_topic(&self.config.ha_discovery_prefix, &topic) {
+                self.on_discovery_removal(&updater, &topic);
+            }
+            return;
+        }
+        if let Some(commands) = self.commands.as_mut() {
+            if commands.is_command_topic(&topic) {
+                commands.handle(updater, &payload);
+                return;
+            }
+        }
+        if self.config.ha_discovery && discovery::is_discovery_topic(&self.config.ha_discovery_prefix, &topic) {
+            self.on_discovery(topic, payload);
+            return;
+        }
+        let parser = match self.data_view_map.get_mut(topic.as_str()) {
+            Some(source) => {
+                source.last_received = Some(Utc::now());
+                source.parser.clone()
+            },
+            None => return,
+        };
+        match parser {
+            DataParser::Callback(callback) => callback(self, updater, payload),
+            DataParser::Fields(fields) => {
+                for field in fields {
+                    let value = field.extract(&payload);
+                    if value.is_none() {
+                        println!("No number at {:?} in payload of topic {:?}", field.path, topic);
+                    }
+                    self.set_target_value(&updater, field.target, value);
+                }
+            },
+        }
+    }
+
+    // Marks the metrics as stale when their sources haven't sent anything for too long
+    pub fn check_freshness(&mut self, updater: WindowUpdater) {
+        let now = Utc::now();
+        let mut stale_metrics = Vec::new();
+        for source in self.data_view_map.values() {
+            let age = match source.last_received {
+                Some(last_received) => (now - last_received).to_std().unwrap_or_default(),
+                None => continue,
+            };
+            if age > source.max_age {
+                stale_metrics.extend(source.metrics.iter().map(|metric| (*metric, age)));
+            }
+        }
+
+        for (metric, age) in stale_metrics {
+            match self.metric_states.get(&metric) {
+                Some(DataStateUI::Valid) => {
+                    self.metric_states.insert(metric, DataStateUI::Stale);
+                    updater.update_metric_state(metric, DataStateUI::Stale);
                 },
-                None => println!("Received unknown topic"),
+                Some(DataStateUI::Stale) => {},
+                _ => continue,
             }
+            updater.update_metric_age(metric, format_age(age));
         }
     }
 
@@ -42,28 +159,143 @@ impl Model {
         }
     }
 
+    // Data sources from the config file
+    pub fn add_sources(&mut self, sources: Vec<SourceConfig>) {
+        for source in sources {
+            let full_topic = expand_topic(&source.topic, self.config.as_ref());
+            let max_age_s = source.max_age_s();
+            let mut data_source = match source.handler {
+                Some(handler) => DataSource::new(handler.callback(), handler.metrics(), max_age_s),
+                None => DataSource::from_fields(source.fields, max_age_s),
+            };
+            data_source.format = source.format;
+            self.data_view_map.insert(full_topic, data_source);
+        }
+    }
+
+    fn on_discovery(&mut self, topic: String, payload: JsonValue) {
+        if !topic.contains(self.config.ha_discovery_filter.as_str()) {
+            return;
+        }
+        let sensor = match discovery::parse_config(&payload) {
+            Ok(Some(sensor)) => sensor,
+            Ok(None) => return,
+            Err(e) => {
+                println!("Discovery: couldn't use sensor {:?}: {}", topic, e);
+                return;
+            }
+        };
+        if self.data_view_map.contains_key(&sensor.state_topic) {
+            return;
+        }
+        // the first sensor of every metric is shown
+        let metric = sensor.field.target.metric();
+        if self.data_view_map.values().any(|source| source.metrics.contains(&metric)) {
+            println!("Discovery: {:?} is already shown, skipping sensor {:?}", metric, topic);
+            return;
+        }
+        println!("Discovery: showing {:?} from {:?}", metric, sensor.state_topic);
+
+        let mut data_source = DataSource::from_fields(vec![sensor.field],
+                                                      sensor.max_age_s.unwrap_or(DEFAULT_MAX_AGE_S));
+        data_source.format = sensor.format;
+        if let Some(subscriber) = self.subscriber.as_mut() {
+            subscriber.subscribe(&sensor.state_topic, sensor.format);
+        }
+        self.discovered.insert(topic, sensor.state_topic.clone());
+        self.data_view_map.insert(sensor.state_topic, data_source);
+    }
+
+    fn on_discovery_removal(&mut self, updater: &WindowUpdater, topic: &str) {
+        let state_topic = match self.discovered.remove(topic) {
+            Some(state_topic) => state_topic,
+            None => return,
+        };
+        if let Some(source) = self.data_view_map.remove(&state_topic) {
+            println!("Discovery: sensor {:?} is removed, hiding {:?}", topic, source.metrics);
+            for metric in source.metrics {
+                self.set_metric_state(updater, metric, false);
+            }
+        }
+    }
+
+    fn set_metric_state(&mut self, updater: &WindowUpdater, metric: Metric, valid: bool) {
+        let state = if valid { DataStateUI::Valid } else { DataStateUI::Unknown };
+        self.metric_states.insert(metric, state);
+        updater.update_metric_state(metric, state);
+    }
+
+    pub fn cycle_history_range(&mut self, updater: WindowUpdater) {
+        self.history_range = self.history_range.next();
+        updater.update_indoor_history_range(self.history_range.label());
+        updater.update_indoor_history_t(self.db.indoor_t_history.get_history(self.history_range));
+        updater.update_indoor_history_rh(self.db.indoor_rh_history.get_history(self.history_range));
+        updater.update_indoor_history_co2(self.db.indoor_co2_history.get_history(self.history_range));
+    }
+
+    fn set_target_value(&mut self, updater: &WindowUpdater, target: Target, value: Option<f32>) {
+        if let Some(value) = value {
+            match target {
+                Target::IndoorT => {
+                    self.db.indoor_t_history.insert(value);
+                    updater.update_indoor_t(value);
+                    updater.update_indoor_history_t(self.db.indoor_t_history.get_history(self.history_range));
+                },
+                Target::IndoorRh => {
+                    self.db.indoor_rh_history.insert(value);
+                    updater.update_indoor_rh(value);
+                    updater.update_indoor_history_rh(self.db.indoor_rh_history.get_history(self.history_range));
+                },
+                Target::IndoorCo2 => {
+                    self.db.indoor_co2_history.insert(value);
+                    updater.update_indoor_co2(value);
+                    updater.update_indoor_history_co2(self.db.indoor_co2_history.get_history(self.history_range));
+                },
+                Target::Kp => {
+                    let now = Utc::now() + chrono::Duration::hours(self.config.timezone_offset_h.into());
+                    updater.update_kp_index_instant(KpIndexUI { hour: now.format("%H").to_string().into(), kp: value });
+                },
+                Target::KpForecast3h => updater.update_kp_forecast_3h(value),
+                Target::KpForecast24h => updater.update_kp_forecast_24h(value),
+                Target::SolarRadiation => updater.update_solar_radiation_now(value),
+                Target::SolarRadiationForecast3h => updater.update_solar_radiation_forecast_3h(value),
+                Target::SolarRadiationForecast24h => updater.update_solar_radiation_forecast_24h(value),
+            }
+        }
+        self.set_metric_state(updater, target.metric(), value.is_some());
+    }
+
     // callbacks
     pub fn indoor_t_rh_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
-        let t = json_data["temperature"].as_i32().unwrap_or(0);
-        let rh = json_data["rh"].as_i32().unwrap_or(0);
-
-        self.db.indoor_t_history.insert(t);
-        self.db.indoor_rh_history.insert(rh);
+        let t = json_data["temperature"].as_f32();
+        let rh = json_data["rh"].as_f32();
 
-        updater.update_indoor_t(t);
-        updater.update_indoor_rh(rh);
-        updater.update_indoor_history_t(self.db.indoor_t_history.get_history());
-        updater.update_indoor_history_rh(self.db.indoor_rh_history.get_history());
+        if let Some(t) = t {
+            self.db.indoor_t_history.insert(t);
+            updater.update_indoor_t(t);
+        }
+        if let Some(rh) = rh {
+            self.db.indoor_rh_history.insert(rh);
+            updater.update_indoor_rh(rh);
+        }
+        self.set_metric_state(&updater, Metric::IndoorT, t.is_some());
+        self.set_metric_state(&updater, Metric::IndoorRh, rh.is_some());
+        updater.update_indoor_history_t(self.db.indoor_t_history.get_history(self.history_range));
+        updater.update_indoor_history_rh(self.db.indoor_rh_history.get_history(self.history_range));
     }
     pub fn indoor_co2_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
-        let value = json_data["co2"].as_i32().unwrap_or(0);
-        self.db.indoor_co2_history.insert(value);
-        updater.update_indoor_co2(value);
-        updater.update_indoor_history_co2(self.db.indoor_co2_history.get_history());
+        let value = json_data["co2"].as_f32();
+        if let Some(value) = value {
+            self.db.indoor_co2_history.insert(value);
+            updater.update_indoor_co2(value);
+        }
+        self.set_metric_state(&updater, Metric::IndoorCo2, value.is_some());
+        updater.update_indoor_history_co2(self.db.indoor_co2_history.get_history(self.history_range));
     }
     pub fn space_weather_kp_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
         if !json_data.is_array() {
             println!("Format of received data is invalid! Should be array of elements");
+            self.set_metric_state(&updater, Metric::KpHistory, false);
             return;
         }
 
@@ -73,40 +305,58 @@ impl Model {
                 println!("Format of received data element is invalid! Should be object of type KpIndexUI");
                 continue;
             }
+            let kp = match element["kp"].as_f32() {
+                Some(kp) => kp,
+                None => {
+                    println!("Received Kp data element doesn't contain Kp value, skipping");
+                    continue;
+                }
+            };
             kp_data.push(KpIndexUI {
                 hour: convert_datetime(element["time_tag"].as_str().unwrap_or("00:00 01-01-2024"),
                                         "%H:%M %d-%m-%Y", "%H", self.config.timezone_offset_h.into()).into(),
-                kp: element["kp"].as_f32().unwrap_or(0.0),
+                kp,
             });
         }
 
+        self.set_metric_state(&updater, Metric::KpHistory, !kp_data.is_empty());
         updater.update_kp_index_data(kp_data);
     }
     pub fn space_weather_kp_inst_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
         if !json_data.is_object() {
             println!("Format of received data is invalid! Shouldn't be object of type KpIndexUI");
+            self.set_metric_state(&updater, Metric::Kp, false);
             return;
         }
-        let kp_inst_val = KpIndexUI {
-            hour: convert_datetime(json_data["time_tag"].as_str().unwrap_or("00:00 01-01-2024"),
-                                    "%H:%M %d-%m-%Y", "%H", self.config.timezone_offset_h.into()).into(),
-            kp: json_data["kp"].as_f32().unwrap_or(0.0),
-        };
-
-        updater.update_kp_index_instant(kp_inst_val);
+        let kp = json_data["kp"].as_f32();
+        if let Some(kp) = kp {
+            let kp_inst_val = KpIndexUI {
+                hour: convert_datetime(json_data["time_tag"].as_str().unwrap_or("00:00 01-01-2024"),
+                                        "%H:%M %d-%m-%Y", "%H", self.config.timezone_offset_h.into()).into(),
+                kp,
+            };
+            updater.update_kp_index_instant(kp_inst_val);
+        }
+        self.set_metric_state(&updater, Metric::Kp, kp.is_some());
     }
     pub fn space_weather_flux_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
         if !json_data.is_array() {
             println!("Format of received data is invalid! Should be array of elements");
+            self.set_metric_state(&updater, Metric::SolarRadiation, false);
             return;
         }
-        let current_flux = json_data[0]["flux_gt10mev"].as_f32().unwrap_or(0.0);
+        let current_flux = json_data[0]["flux_gt10mev"].as_f32();
         println!("current flux greater 10 Mev: {:#?}", current_flux);
-        updater.update_solar_radiation_now(current_flux);
+        if let Some(current_flux) = current_flux {
+            updater.update_solar_radiation_now(current_flux);
+        }
+        self.set_metric_state(&updater, Metric::SolarRadiation, current_flux.is_some());
     }
     pub fn space_weather_forecast_callback(&mut self, updater: WindowUpdater, json_data: JsonValue) {
         if !json_data.is_object() {
             println!("Format of received data is invalid! Should be object");
+            self.set_metric_state(&updater, Metric::KpForecast, false);
+            self.set_metric_state(&updater, Metric::SolarRadiationForecast, false);
             return;
         }
 
@@ -118,6 +368,8 @@ impl Model {
 
         if !(json_data.has_key("kp") && json_data.has_key("srs") && json_data.has_key("rb")) {
             println!("Received json data does not contain the necessary keys!");
+            self.set_metric_state(&updater, Metric::KpForecast, false);
+            self.set_metric_state(&updater, Metric::SolarRadiationForecast, false);
             return;
         }
 
@@ -129,9 +381,11 @@ impl Model {
                     println!("kpf_3h: {:#?}, kpf_1d: {:#?}, kpf_3d: {:#?}", kpf_3h, kpf_1d, kpf_3d);
                     updater.update_kp_forecast_3h(kpf_3h.into());
                     updater.update_kp_forecast_24h(kpf_1d.into());
+                    self.set_metric_state(&updater, Metric::KpForecast, true);
                 },
                 None => {
                     println!("Couldn't extract Kp forecast from MQTT data");
+                    self.set_metric_state(&updater, Metric::KpForecast, false);
                 }
             }
         }
@@ -142,9 +396,11 @@ impl Model {
                 // FIXME: temporary using 1d forecast data as 3h forecast
                 updater.update_solar_radiation_forecast_3h(srs_1d.into());
                 updater.update_solar_radiation_forecast_24h(srs_1d.into());
+                self.set_metric_state(&updater, Metric::SolarRadiationForecast, true);
             },
             None => {
                 println!("Couldn't extract SRS forecast from MQTT data");
+                self.set_metric_state(&updater, Metric::SolarRadiationForecast, false);
             }
         }
 
@@ -161,6 +417,22 @@ impl Model {
 }
 
 
+// Short human readable age, e.g. "42 min"
+fn format_age(age: Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    if minutes < 60 {
+        format!("{} min", minutes)
+    } else if minutes < 48 * 60 {
+        format!("{} h", minutes / 60)
+    } else {
+        format!("{} d", minutes / (24 * 60))
+    }
+}
+
+fn expand_topic(template: &str, config: &Config) -> String {
+    template.replace("{base}", &config.mqtt_base_topic).replace("{controller}", &config.mqtt_controller_name)
+}
+
 fn make_full_topic(sensor_name: &str, config: &Config) -> String {
     let full_topic = config.mqtt_base_topic.clone() + "/" + &config.mqtt_controller_name + "_" + sensor_name + "/state";
     return full_topic;
@@ -179,7 +451,7 @@ fn extract_kp_forecast(kp_vec_json: &JsonValue, current_datetime: DateTime<Utc>)
         println!("Format of 'kp' data is invalid! Should be array of elements");
         return None;
     }
-    let mut kp_3h = 0.0;
+    let mut kp_3h = None;
     let mut kp_1d = 0.0;
     let mut kp_3d = 0.0;
     let mut interval_1d_started: bool = false;
@@ -187,7 +459,8 @@ fn extract_kp_forecast(kp_vec_json: &JsonValue, current_datetime: DateTime<Utc>)
     let mut interval_1d_counter = 0;
     for kp in kp_vec_json.members() {
         let date = kp["date"].as_str().unwrap_or_default();
-        let kp_val = kp["value"].as_f32().unwrap_or_default();
+        // a missing value is only an error within the forecast interval
+        let kp_val = kp["value"].as_f32();
         if date == current_date {
             let mut hour = match kp["hour"].as_u32() {
                 Some(val) => val,
@@ -201,7 +474,7 @@ fn extract_kp_forecast(kp_vec_json: &JsonValue, current_datetime: DateTime<Utc>)
             }
             let interval_start = if hour < 3 { 0 } else { hour - 3 };
             if current_hour <= hour && current_hour >= interval_start {
-                kp_3h = kp_val;
+                kp_3h = Some(kp_val?);
                 interval_1d_started = true;
                 interval_3d_started = true;
             }
@@ -209,14 +482,15 @@ fn extract_kp_forecast(kp_vec_json: &JsonValue, current_datetime: DateTime<Utc>)
         if interval_1d_started {
             if interval_1d_counter < 8 {
                 interval_1d_counter = interval_1d_counter + 1;
-                kp_1d = if kp_val > kp_1d { kp_val } else { kp_1d };
+                kp_1d = f32::max(kp_1d, kp_val?);
             }
         }
         if interval_3d_started {
-            kp_3d = if kp_val > kp_3d { kp_val } else { kp_3d };
+            kp_3d = f32::max(kp_3d, kp_val?);
         }
     }
-    return Some((kp_3h, kp_1d, kp_3d));
+    // no forecast for the current time
+    return Some((kp_3h?, kp_1d, kp_3d));
 }
 
 // Data format:
@@ -230,21 +504,35 @@ fn extract_kp_forecast(kp_vec_json: &JsonValue, current_datetime: DateTime<Utc>)
 // }
 fn extract_srs_rb_forecast(srs_vec_json: &JsonValue, current_datetime: DateTime<Utc>, min_prob_thrh: u8) -> Option<(f32, f32)> {
     let current_date = current_datetime.format("%b %d %Y").to_string();
+    if !srs_vec_json.is_array() {
+        println!("Format of 'srs'/'rb' data is invalid! Should be array of elements");
+        return None;
+    }
 
     let mut srs_1d_max_storm_level = 0;
     let mut srs_3d_max_storm_level = 0;
     let mut interval_3d_started: bool = false;
     for srs in srs_vec_json.members() {
         let date = srs["date"].as_str().unwrap_or_default();
-        let (max_storm_level, _) = get_max_storm(srs, min_prob_thrh).unwrap_or((0, 0));
         if date == current_date {
-            srs_1d_max_storm_level = max_storm_level;
             interval_3d_started = true;
         }
-        if interval_3d_started && max_storm_level > srs_3d_max_storm_level {
+        // entries before the current date aren't used
+        if !interval_3d_started {
+            continue;
+        }
+        let (max_storm_level, _) = get_max_storm(srs, min_prob_thrh)?;
+        if date == current_date {
+            srs_1d_max_storm_level = max_storm_level;
+        }
+        if max_storm_level > srs_3d_max_storm_level {
             srs_3d_max_storm_level = max_storm_level;
         }
     }
+    // no forecast for the current date
+    if !interval_3d_started {
+        return None;
+    }
 
     let srs_1d = convert_srs_level_to_flux(srs_1d_max_storm_level);
     let srs_3d = convert_srs_level_to_flux(srs_3d_max_storm_level);
@@ -253,11 +541,11 @@ fn extract_srs_rb_forecast(srs_vec_json: &JsonValue, current_datetime: DateTime<
 
 fn get_max_storm(srs_json: &JsonValue, prob_thrh: u8) -> Option<(u8, u8)> {
     let srs = [
-        srs_json["s5"].as_u8().unwrap_or(0),
-        srs_json["s4"].as_u8().unwrap_or(0),
-        srs_json["s3"].as_u8().unwrap_or(0),
-        srs_json["s2"].as_u8().unwrap_or(0),
-        srs_json["s1"].as_u8().unwrap_or(0)
+        srs_json["s5"].as_u8()?,
+        srs_json["s4"].as_u8()?,
+        srs_json["s3"].as_u8()?,
+        srs_json["s2"].as_u8()?,
+        srs_json["s1"].as_u8()?
     ];
     let mut max_storm_level: u8 = 0;
     let mut probability: u8 = 0;
@@ -287,7 +575,13 @@ fn convert_srs_level_to_flux(level: u8) -> f32 {
 }
 
 fn convert_datetime(input: &str, in_format: &str, out_format: &str, offset_hours: i64) -> String {
-    let mut datetime = NaiveDateTime::parse_from_str(input, in_format).expect("Failed to parse datetime");
+    let mut datetime = match NaiveDateTime::parse_from_str(input, in_format) {
+        Ok(datetime) => datetime,
+        Err(e) => {
+            println!("Failed to parse datetime {:?}: {}", input, e);
+            return String::new();
+        }
+    };
     datetime += chrono::Duration::hours(offset_hours);
     return datetime.format(out_format).to_string();
 }
@@ -0,0 +1,161 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use json::object;
+use crate::Config;
+use crate::backlight::Backlight;
+use crate::connector::MQTTPublisher;
+use crate::ui::WindowUpdater;
+use crate::video::FrameObserver;
+
+// every STEP-th pixel of every STEP-th row is compared
+const STEP: usize = 4;
+
+// Region of interest as fractions of the frame: "x,y,width,height", e.g. "0,0.5,1,0.5" - the lower half
+#[derive(Debug, Clone, Copy)]
+pub struct Roi {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32
+}
+
+impl FromStr for Roi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s.split(',').map(|value| value.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid region '{}'", s))?;
+        let roi = match values[..] {
+            [x, y, width, height] => Roi { x, y, width, height },
+            _ => return Err(format!("Region '{}' should be 'x,y,width,height'", s)),
+        };
+        let valid = |value: f32| (0.0..=1.0).contains(&value);
+        if !valid(roi.x) || !valid(roi.y) || !valid(roi.x + roi.width) || !valid(roi.y + roi.height)
+            || roi.width <= 0.0 || roi.height <= 0.0 {
+            return Err(format!("Region '{}' should be within 0..1", s));
+        }
+        Ok(roi)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MotionConfig {
+    pub roi: Roi,
+    // min difference of pixel brightness (0..255)
+    pub pixel_threshold: u8,
+    // min share of changed pixels in the region, percent
+    pub min_area_prcnt: f32,
+    // motion ends when there is no motion for this time
+    pub hold: Duration
+}
+
+// Frame difference detector working on the frames shown by the UI
+pub struct MotionDetector {
+    config: MotionConfig,
+    // brightness of the sampled pixels of the previous frame
+    previous: Vec<u8>,
+    size: (u32, u32),
+    last_motion: Option<Instant>
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        Self { config, previous: Vec::new(), size: (0, 0), last_motion: None }
+    }
+
+    // Starts over, e.g. after switching the video source
+    pub fn reset(&mut self) {
+        self.previous.clear();
+    }
+
+    // Returns Some(true) when motion starts and Some(false) when it ends, the changed area in percent is
+    // returned with them
+    pub fn process(&mut self, frame: &slint::SharedPixelBuffer<slint::Rgb8Pixel>) -> Option<(bool, f32)> {
+        let samples = self.sample(frame);
+        let area_prcnt = if samples.len() == self.previous.len() && !samples.is_empty() {
+            let changed = samples.iter().zip(&self.previous)
+                .filter(|(sample, previous)| sample.abs_diff(**previous) >= self.config.pixel_threshold)
+                .count();
+            changed as f32 * 100.0 / samples.len() as f32
+        } else {
+            0.0
+        };
+        self.previous = samples;
+
+        let now = Instant::now();
+        if area_prcnt >= self.config.min_area_prcnt {
+            let started = self.last_motion.is_none();
+            self.last_motion = Some(now);
+            return started.then_some((true, area_prcnt));
+        }
+        match self.last_motion {
+            Some(last_motion) if now.duration_since(last_motion) > self.config.hold => {
+                self.last_motion = None;
+                Some((false, area_prcnt))
+            },
+            _ => None,
+        }
+    }
+
+    fn sample(&mut self, frame: &slint::SharedPixelBuffer<slint::Rgb8Pixel>) -> Vec<u8> {
+        let (width, height) = (frame.width(), frame.height());
+        if self.size != (width, height) {
+            self.size = (width, height);
+            self.previous.clear();
+        }
+        let roi = &self.config.roi;
+        let x0 = (roi.x * width as f32) as usize;
+        let x1 = ((roi.x + roi.width) * width as f32) as usize;
+        let y0 = (roi.y * height as f32) as usize;
+        let y1 = ((roi.y + roi.height) * height as f32) as usize;
+
+        let pixels = frame.as_slice();
+        let mut samples = Vec::with_capacity((x1 - x0) * (y1 - y0) / (STEP * STEP) + 1);
+        for y in (y0..y1).step_by(STEP) {
+            let row = &pixels[y * width as usize..(y + 1) * width as usize];
+            for pixel in row[x0..x1].iter().step_by(STEP) {
+                samples.push(((u16::from(pixel.r) + 2 * u16::from(pixel.g) + u16::from(pixel.b)) / 4) as u8);
+            }
+        }
+        samples
+    }
+}
+
+pub fn motion_topic(config: &Config) -> String {
+    config.mqtt_display_topic.clone() + "/motion"
+}
+
+// Runs the detector on the video frames. On motion the video box is highlighted, the screen is woken up if
+// MOTION_WAKE is set and {"motion": true, "area_prcnt": 4.2} is published to the motion topic,
+// {"motion": false, ...} is published when it ends.
+pub fn frame_observer(config: &Config, motion: MotionConfig, updater: WindowUpdater,
+                      mut publisher: MQTTPublisher) -> FrameObserver {
+    let mut detector = MotionDetector::new(motion);
+    let topic = motion_topic(config);
+    let wake = config.motion_wake;
+    let backlight = Backlight::open(&config.backlight_path);
+    Box::new(move |frame, discontinuity| {
+        if discontinuity {
+            detector.reset();
+        }
+        let (motion, area_prcnt) = match detector.process(frame) {
+            Some(event) => event,
+            None => return,
+        };
+        println!("Motion: {} ({:.1}% changed)", if motion { "started" } else { "ended" }, area_prcnt);
+        updater.update_video_motion(motion);
+        if motion && wake {
+            if let Some(backlight) = &backlight {
+                if let Err(e) = backlight.set_power(true) {
+                    println!("Couldn't turn on backlight: {:?}", e);
+                }
+            }
+            updater.update_blank(false);
+        }
+        let event = object! {
+            motion: motion,
+            area_prcnt: (area_prcnt * 10.0).round() / 10.0
+        };
+        publisher.publish(&topic, event.dump(), false);
+    })
+}
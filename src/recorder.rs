@@ -0,0 +1,189 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+
+use gst::prelude::*;
+
+const RECORDING_PREFIX: &str = "rec_";
+const RECORDING_EXTENSION: &str = "mkv";
+
+#[derive(Debug, Clone)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub segment: Duration,
+    // the oldest segments are removed when any limit is exceeded, 0 - no limit
+    pub max_bytes: u64,
+    pub max_age: Duration,
+    // segments are named with the local time
+    pub timezone_offset_h: i8
+}
+
+// Recording branch attached to the tee of the video pipeline, the tee gets the parsed stream before decoding:
+//  tee -> queue -> parsebin -> splitmuxsink
+// The stream isn't re-encoded, parsebin only converts it to the format of the muxer. Segments are Matroska
+// files, they stay playable if the display is turned off while recording.
+pub struct Recorder {
+    bin: gst::Bin,
+    tee_pad: gst::Pad
+}
+
+impl Recorder {
+    pub fn attach(pipeline: &gst::Pipeline, tee: &gst::Element, config: &RecordingConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir).with_context(|| format!("Couldn't create dir {:?}", config.dir))?;
+
+        // unique name, the previous branch can still be finishing its segment
+        let bin = gst::Bin::new();
+        let queue = gst::ElementFactory::make("queue").build()?;
+        let parsebin = gst::ElementFactory::make("parsebin").build()?;
+        let (dir, timezone_offset_h) = (config.dir.clone(), config.timezone_offset_h);
+        let sink = gst::ElementFactory::make("splitmuxsink")
+            .property_from_str("muxer-factory", "matroskamux")
+            .property("max-size-time", config.segment.as_nanos() as u64)
+            .build()?;
+        sink.connect("format-location", false, move |_| {
+            Some(segment_path(&dir, timezone_offset_h).to_string_lossy().to_value())
+        });
+
+        bin.add_many([&queue, &parsebin, &sink])?;
+        queue.link(&parsebin)?;
+        let sink_weak = sink.downgrade();
+        parsebin.connect_pad_added(move |_, src_pad| {
+            let sink = match sink_weak.upgrade() {
+                Some(sink) => sink,
+                None => return,
+            };
+            match sink.request_pad_simple("video") {
+                Some(sink_pad) => if let Err(e) = src_pad.link(&sink_pad) {
+                    println!("Recorder: couldn't link stream: {:?}", e);
+                },
+                // only one video stream is recorded
+                None => println!("Recorder: extra stream {:?} isn't recorded", src_pad.name()),
+            }
+        });
+        let queue_pad = queue.static_pad("sink").unwrap();
+        // segment should start with a key frame, the stream is joined in the middle
+        queue_pad.add_probe(gst::PadProbeType::BUFFER, |_, info| {
+            match info.buffer() {
+                Some(buffer) if buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) => gst::PadProbeReturn::Drop,
+                _ => gst::PadProbeReturn::Remove,
+            }
+        });
+        bin.add_pad(&gst::GhostPad::with_target(&queue_pad)?)?;
+
+        pipeline.add(&bin)?;
+        let tee_pad = match Self::link(tee, &bin) {
+            Ok(tee_pad) => tee_pad,
+            Err(e) => {
+                Self::remove(pipeline, &bin);
+                return Err(e);
+            },
+        };
+        println!("Recorder: started in {:?}", config.dir);
+        Ok(Self { bin, tee_pad })
+    }
+
+    fn link(tee: &gst::Element, bin: &gst::Bin) -> Result<gst::Pad> {
+        let tee_pad = tee.request_pad_simple("src_%u").ok_or_else(|| anyhow!("Couldn't get tee pad"))?;
+        let linked = tee_pad.link(&bin.static_pad("sink").unwrap()).map_err(anyhow::Error::from)
+            .and_then(|_| bin.sync_state_with_parent().map_err(anyhow::Error::from));
+        if let Err(e) = linked {
+            tee.release_request_pad(&tee_pad);
+            return Err(e);
+        }
+        Ok(tee_pad)
+    }
+
+    // Unlinks the branch and finishes the segment, the branch is removed by `remove` when the segment is closed
+    pub fn detach(self, tee: &gst::Element) -> gst::Bin {
+        let tee = tee.clone();
+        let bin_pad = self.bin.static_pad("sink").unwrap();
+        self.tee_pad.add_probe(gst::PadProbeType::IDLE, move |tee_pad, _| {
+            if tee_pad.unlink(&bin_pad).is_ok() {
+                tee.release_request_pad(tee_pad);
+                bin_pad.send_event(gst::event::Eos::new());
+            }
+            gst::PadProbeReturn::Remove
+        });
+        println!("Recorder: stopped");
+        self.bin
+    }
+
+    // Drops the branch at once after its failure, the segment can stay unfinished
+    pub fn abort(self, pipeline: &gst::Pipeline, tee: &gst::Element) {
+        let _ = self.tee_pad.unlink(&self.bin.static_pad("sink").unwrap());
+        tee.release_request_pad(&self.tee_pad);
+        Self::remove(pipeline, &self.bin);
+        println!("Recorder: aborted");
+    }
+
+    pub fn contains(&self, object: &gst::Object) -> bool {
+        object.has_as_ancestor(&self.bin)
+    }
+
+    pub fn remove(pipeline: &gst::Pipeline, bin: &gst::Bin) {
+        if let Err(e) = bin.set_state(gst::State::Null) {
+            println!("Recorder: couldn't stop branch: {:?}", e);
+        }
+        if let Err(e) = pipeline.remove(bin) {
+            println!("Recorder: couldn't remove branch: {:?}", e);
+        }
+    }
+}
+
+// "splitmuxsink-fragment-closed" message
+pub fn is_segment_closed(message: &gst::Message) -> bool {
+    match message.view() {
+        gst::MessageView::Element(element) =>
+            element.structure().map(|structure| structure.name() == "splitmuxsink-fragment-closed") == Some(true),
+        _ => false,
+    }
+}
+
+fn segment_path(dir: &Path, timezone_offset_h: i8) -> PathBuf {
+    let now = Utc::now() + chrono::Duration::hours(timezone_offset_h.into());
+    dir.join(format!("{}{}.{}", RECORDING_PREFIX, now.format("%Y-%m-%d_%H-%M-%S"), RECORDING_EXTENSION))
+}
+
+// Recorded segments from the oldest one
+pub fn recordings(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect(),
+        Err(_) => return Vec::new(),
+    };
+    paths.retain(|path| {
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        name.starts_with(RECORDING_PREFIX) && path.extension().map(|ext| ext == RECORDING_EXTENSION) == Some(true)
+    });
+    // names have the start time
+    paths.sort();
+    paths
+}
+
+// Removes the oldest segments exceeding the size or age limit
+pub fn apply_retention(config: &RecordingConfig) {
+    let segments: Vec<(PathBuf, u64, SystemTime)> = recordings(&config.dir).into_iter()
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            Some((path, metadata.len(), metadata.modified().ok()?))
+        })
+        .collect();
+    let mut total_bytes: u64 = segments.iter().map(|(_, size, _)| size).sum();
+    // the newest segment is kept as it can be still written
+    for (path, size, modified) in segments.iter().take(segments.len().saturating_sub(1)) {
+        let too_old = !config.max_age.is_zero()
+            && modified.elapsed().map(|age| age > config.max_age).unwrap_or(false);
+        let too_big = config.max_bytes > 0 && total_bytes > config.max_bytes;
+        if !too_old && !too_big {
+            break;
+        }
+        match fs::remove_file(path) {
+            Ok(()) => {
+                println!("Recorder: removed {:?}", path);
+                total_bytes -= size;
+            },
+            Err(e) => println!("Recorder: couldn't remove {:?}: {:?}", path, e),
+        }
+    }
+}
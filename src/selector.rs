@@ -0,0 +1,191 @@
+use std::fmt;
+use json::JsonValue;
+use serde::Deserialize;
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Key(String),
+    // negative index counts from the end of array
+    Index(i64)
+}
+
+// JSONPath-like location of a value inside the payload, e.g.
+//  "temperature", "SI7021.Temperature", "$.data[0].t", "$['key.with.dots']", "values[-1]"
+// An empty path or "$" selects the whole payload.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Selector {
+    path: String,
+    steps: Vec<Step>
+}
+
+impl Selector {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let mut steps = Vec::new();
+        let mut chars = path.trim().chars().peekable();
+        if chars.peek() == Some(&'$') {
+            chars.next();
+            if chars.peek() == Some(&'.') {
+                chars.next();
+            }
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => push_key(&mut steps, &mut key, path)?,
+                '[' => {
+                    if !key.is_empty() {
+                        push_key(&mut steps, &mut key, path)?;
+                    }
+                    let mut inner = String::new();
+                    let mut quote: Option<char> = None;
+                    // "['0']" is a key, "[0]" is an index
+                    let mut quoted = false;
+                    loop {
+                        match (chars.next(), quote) {
+                            (None, _) => return Err(format!("Unclosed '[' in path {:?}", path)),
+                            (Some(c), Some(q)) if c == q => quote = None,
+                            (Some(c), Some(_)) => inner.push(c),
+                            (Some(c @ ('"' | '\'')), None) => {
+                                quote = Some(c);
+                                quoted = true;
+                            },
+                            (Some(']'), None) => break,
+                            (Some(c), None) => inner.push(c),
+                        }
+                    }
+                    let inner_trimmed = inner.trim();
+                    match inner_trimmed.parse::<i64>() {
+                        Ok(index) if !quoted && inner == inner_trimmed => steps.push(Step::Index(index)),
+                        _ if !inner.is_empty() => steps.push(Step::Key(inner)),
+                        _ => return Err(format!("Empty '[]' in path {:?}", path)),
+                    }
+                    // a key has to be separated from the brackets by a dot
+                    if let Some(next) = chars.peek() {
+                        if *next != '.' && *next != '[' {
+                            return Err(format!("Unexpected {:?} after ']' in path {:?}", next, path));
+                        }
+                    }
+                    if chars.peek() == Some(&'.') {
+                        chars.next();
+                        if chars.peek().is_none() {
+                            return Err(format!("Path {:?} ends with '.'", path));
+                        }
+                    }
+                },
+                _ => key.push(c),
+            }
+        }
+        if !key.is_empty() {
+            push_key(&mut steps, &mut key, path)?;
+        }
+        Ok(Self { path: path.to_owned(), steps })
+    }
+
+    // Selected value or null if there is no such value
+    pub fn select<'a>(&self, json_data: &'a JsonValue) -> &'a JsonValue {
+        let mut value = json_data;
+        for step in &self.steps {
+            value = match (step, value) {
+                (Step::Index(index), JsonValue::Array(array)) => {
+                    let index = if *index < 0 { array.len() as i64 + index } else { *index };
+                    if index < 0 {
+                        return &JsonValue::Null;
+                    }
+                    &value[index as usize]
+                },
+                // "data.0.t" is the same as "data[0].t"
+                (Step::Key(key), JsonValue::Array(_)) => match key.parse::<usize>() {
+                    Ok(index) => &value[index],
+                    Err(_) => return &JsonValue::Null,
+                },
+                (Step::Key(key), _) => &value[key.as_str()],
+                (Step::Index(_), _) => return &JsonValue::Null,
+            };
+        }
+        value
+    }
+}
+
+impl TryFrom<String> for Selector {
+    type Error = String;
+
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        Selector::parse(&path)
+    }
+}
+
+impl fmt::Debug for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.path)
+    }
+}
+
+fn push_key(steps: &mut Vec<Step>, key: &mut String, path: &str) -> Result<(), String> {
+    if key.is_empty() {
+        return Err(format!("Empty key in path {:?}", path));
+    }
+    steps.push(Step::Key(std::mem::take(key)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(path: &str, payload: &str) -> JsonValue {
+        Selector::parse(path).unwrap().select(&json::parse(payload).unwrap()).clone()
+    }
+
+    #[test]
+    fn plain_and_nested_keys() {
+        assert_eq!(select("temperature", r#"{"temperature": 21.5}"#), 21.5);
+        assert_eq!(select("SI7021.Temperature", r#"{"SI7021": {"Temperature": 22}}"#), 22);
+        assert_eq!(select("$.data[0].t", r#"{"data": [{"t": 1}, {"t": 2}]}"#), 1);
+        assert!(select("missing.key", r#"{"a": 1}"#).is_null());
+    }
+
+    #[test]
+    fn whole_payload() {
+        assert_eq!(select("", "42"), 42);
+        assert_eq!(select("$", "[1, 2]"), json::array![1, 2]);
+    }
+
+    #[test]
+    fn quoted_keys() {
+        assert_eq!(select("$['key.with.dots']", r#"{"key.with.dots": 1}"#), 1);
+        assert_eq!(select(r#"$["a b"].c"#, r#"{"a b": {"c": 2}}"#), 2);
+        assert_eq!(select("$['x]y']", r#"{"x]y": 3}"#), 3);
+        assert_eq!(select("a['b'][1]", r#"{"a": {"b": [4, 5]}}"#), 5);
+    }
+
+    #[test]
+    fn quoted_number_is_key() {
+        assert_eq!(Selector::parse("$['0']").unwrap().steps, vec![Step::Key("0".to_owned())]);
+        assert_eq!(Selector::parse("$[0]").unwrap().steps, vec![Step::Index(0)]);
+        assert_eq!(select("$['0']", r#"{"0": "zero"}"#), "zero");
+        assert!(select("$[0]", r#"{"0": "zero"}"#).is_null());
+    }
+
+    #[test]
+    fn negative_indices() {
+        assert_eq!(select("values[-1]", r#"{"values": [1, 2, 3]}"#), 3);
+        assert_eq!(select("values[-3]", r#"{"values": [1, 2, 3]}"#), 1);
+        assert!(select("values[-4]", r#"{"values": [1, 2, 3]}"#).is_null());
+        assert!(select("values[5]", r#"{"values": [1, 2, 3]}"#).is_null());
+    }
+
+    #[test]
+    fn dotted_index() {
+        assert_eq!(select("data.1.t", r#"{"data": [{"t": 1}, {"t": 2}]}"#), 2);
+        assert!(select("data.x", r#"{"data": [1]}"#).is_null());
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in ["a..b", ".a", "a[", "a[]", "a[0]b", "a[0].", "a['b"] {
+            assert!(Selector::parse(path).is_err(), "{:?} should be invalid", path);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use crate::video::VideoPlayer;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SnapshotFormat {
+    Png,
+    Jpeg
+}
+
+impl SnapshotFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            SnapshotFormat::Png => "png",
+            SnapshotFormat::Jpeg => "jpg",
+        }
+    }
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(SnapshotFormat::Png),
+            "jpeg" | "jpg" => Ok(SnapshotFormat::Jpeg),
+            _ => Err(format!("Unknown snapshot format '{}'", s)),
+        }
+    }
+}
+
+// Stills of the video saved as "<dir>/snapshot_<local time with ms>.<png|jpg>", a counter is added to the name
+// of snapshots taken in the same millisecond
+#[derive(Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    format: SnapshotFormat,
+    timezone_offset_h: i8,
+    video: VideoPlayer
+}
+
+impl Snapshots {
+    pub fn open(dir: &str, format: SnapshotFormat, timezone_offset_h: i8, video: VideoPlayer) -> Option<Self> {
+        if dir.is_empty() {
+            return None;
+        }
+        if let Err(e) = fs::create_dir_all(dir) {
+            println!("Snapshot: couldn't create dir {:?}: {:?}", dir, e);
+            return None;
+        }
+        Some(Self { dir: Path::new(dir).to_owned(), format, timezone_offset_h, video })
+    }
+
+    // Saves the last frame passed to the UI, returns the file path
+    pub fn take(&self) -> Result<PathBuf> {
+        let frame = self.video.last_frame().ok_or_else(|| anyhow!("no video frame yet"))?;
+        let (path, file) = self.create_file()?;
+        let format = match self.format {
+            SnapshotFormat::Png => image::ImageFormat::Png,
+            SnapshotFormat::Jpeg => image::ImageFormat::Jpeg,
+        };
+        let result = image::write_buffer_with_format(&mut BufWriter::new(file), frame.as_bytes(), frame.width(),
+                                                     frame.height(), image::ColorType::Rgb8, format);
+        if let Err(e) = result {
+            let _ = fs::remove_file(&path);
+            return Err(e).with_context(|| format!("Couldn't save {:?}", path));
+        }
+        println!("Snapshot: saved {:?}", path);
+        Ok(path)
+    }
+
+    // Creates a new file, an existing snapshot is never overwritten
+    fn create_file(&self) -> Result<(PathBuf, File)> {
+        let now = Utc::now() + chrono::Duration::hours(self.timezone_offset_h.into());
+        let stem = format!("snapshot_{}", now.format("%Y-%m-%d_%H-%M-%S-%3f"));
+        let mut counter = 0;
+        loop {
+            let name = match counter {
+                0 => format!("{}.{}", stem, self.format.extension()),
+                _ => format!("{}_{}.{}", stem, counter, self.format.extension()),
+            };
+            let path = self.dir.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => counter += 1,
+                Err(e) => return Err(e).with_context(|| format!("Couldn't create {:?}", path)),
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use json::JsonValue;
+use crate::model::{DataCallback, Metric, Model};
+use crate::selector::Selector;
+use crate::decoder::PayloadFormat;
+
+// max age of a data source when it isn't set in the file
+pub const DEFAULT_MAX_AGE_S: u64 = 300;
+
+// Data sources file, e.g.
+//  [[source]]
+//  topic = "{base}/{controller}_htu21d/state"
+//  max_age_s = 300
+//  [[source.field]]
+//  path = "SI7021.Temperature"
+//  target = "indoor_t"
+//  unit = "F"
+#[derive(Debug, Deserialize)]
+struct SourcesFile {
+    #[serde(rename = "source", default)]
+    sources: Vec<SourceConfig>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    // full topic, `{base}` and `{controller}` are replaced with MQTT_BROKER_BASE_TOPIC and MQTT_CONTROLLER_NAME
+    pub topic: String,
+    pub max_age_s: Option<u64>,
+    // json (default), scalar, csv or key_value, see `PayloadFormat`
+    #[serde(default)]
+    pub format: PayloadFormat,
+    // built-in parser of the whole payload, e.g. for NOAA data
+    pub handler: Option<Handler>,
+    #[serde(rename = "field", default)]
+    pub fields: Vec<FieldMapping>
+}
+
+impl SourceConfig {
+    pub fn max_age_s(&self) -> u64 {
+        self.max_age_s.unwrap_or(DEFAULT_MAX_AGE_S)
+    }
+}
+
+// Value taken from the payload and shown by the widget
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMapping {
+    // location of the value, see `Selector`
+    pub path: Selector,
+    pub target: Target,
+    // the value is converted as `value * scale + offset` and then from `unit` to the displayed unit
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    #[serde(default)]
+    pub offset: f32,
+    pub unit: Option<Unit>
+}
+
+impl FieldMapping {
+    // Numbers sent as strings, e.g. "23.4", are accepted too, "NaN" or "inf" and overflows are not
+    pub fn extract(&self, json_data: &JsonValue) -> Option<f32> {
+        let value = self.path.select(json_data);
+        let value = value.as_f32().or_else(|| value.as_str().and_then(|value| value.trim().parse().ok()))?;
+        let value = value * self.scale + self.offset;
+        let value = match self.unit {
+            Some(unit) => unit.to_celsius(value),
+            None => value,
+        };
+        value.is_finite().then_some(value)
+    }
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+// Temperature units, temperatures are always shown in °C
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin
+}
+
+impl Unit {
+    pub fn to_celsius(&self, value: f32) -> f32 {
+        match self {
+            Unit::Celsius => value,
+            Unit::Fahrenheit => (value - 32.0) * 5.0 / 9.0,
+            Unit::Kelvin => value - 273.15,
+        }
+    }
+}
+
+impl FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim() {
+            "C" | "°C" => Ok(Unit::Celsius),
+            "F" | "°F" => Ok(Unit::Fahrenheit),
+            "K" => Ok(Unit::Kelvin),
+            _ => Err(format!("Unknown unit '{}'", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Unit {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Adapter properties which can be fed by a single value
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Target {
+    IndoorT,
+    IndoorRh,
+    IndoorCo2,
+    Kp,
+    #[serde(rename = "kp_forecast_3h")]
+    KpForecast3h,
+    #[serde(rename = "kp_forecast_24h")]
+    KpForecast24h,
+    SolarRadiation,
+    #[serde(rename = "solar_radiation_forecast_3h")]
+    SolarRadiationForecast3h,
+    #[serde(rename = "solar_radiation_forecast_24h")]
+    SolarRadiationForecast24h
+}
+
+impl Target {
+    pub fn metric(&self) -> Metric {
+        match self {
+            Target::IndoorT => Metric::IndoorT,
+            Target::IndoorRh => Metric::IndoorRh,
+            Target::IndoorCo2 => Metric::IndoorCo2,
+            Target::Kp => Metric::Kp,
+            Target::KpForecast3h | Target::KpForecast24h => Metric::KpForecast,
+            Target::SolarRadiation => Metric::SolarRadiation,
+            Target::SolarRadiationForecast3h | Target::SolarRadiationForecast24h => Metric::SolarRadiationForecast,
+        }
+    }
+}
+
+// Built-in payload parsers of 'Weather Provider' data
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Handler {
+    Htu21d,
+    Mhz19,
+    NoaaKp,
+    NoaaKpInst,
+    NoaaFlux,
+    NoaaSwForecast
+}
+
+impl Handler {
+    pub fn callback(&self) -> DataCallback {
+        match self {
+            Handler::Htu21d => Model::indoor_t_rh_callback,
+            Handler::Mhz19 => Model::indoor_co2_callback,
+            Handler::NoaaKp => Model::space_weather_kp_callback,
+            Handler::NoaaKpInst => Model::space_weather_kp_inst_callback,
+            Handler::NoaaFlux => Model::space_weather_flux_callback,
+            Handler::NoaaSwForecast => Model::space_weather_forecast_callback,
+        }
+    }
+    pub fn metrics(&self) -> Vec<Metric> {
+        match self {
+            Handler::Htu21d => vec![Metric::IndoorT, Metric::IndoorRh],
+            Handler::Mhz19 => vec![Metric::IndoorCo2],
+            Handler::NoaaKp => vec![Metric::KpHistory],
+            Handler::NoaaKpInst => vec![Metric::Kp],
+            Handler::NoaaFlux => vec![Metric::SolarRadiation],
+            Handler::NoaaSwForecast => vec![Metric::KpForecast, Metric::SolarRadiationForecast],
+        }
+    }
+}
+
+pub fn load_sources(path: &str) -> Result<Vec<SourceConfig>> {
+    let content = fs::read_to_string(path).with_context(|| format!("Couldn't read data sources file {:?}", path))?;
+    let file: SourcesFile = toml::from_str(&content)
+        .with_context(|| format!("Couldn't parse data sources file {:?}", path))?;
+    for source in &file.sources {
+        match (&source.handler, source.fields.is_empty()) {
+            (Some(_), false) => bail!("Source {:?} has both handler and fields", source.topic),
+            (None, true) => bail!("Source {:?} has neither handler nor fields", source.topic),
+            _ => {},
+        }
+        for (index, field) in source.fields.iter().enumerate() {
+            if field.unit.is_some() && field.target != Target::IndoorT {
+                bail!("Source {:?}: unit can be set only for temperature, use scale and offset instead",
+                      source.topic);
+            }
+            if source.fields[..index].iter().any(|other| other.target == field.target) {
+                bail!("Source {:?}: target {:?} is set twice", source.topic, field.target);
+            }
+        }
+    }
+    // readings of several sources would be mixed in one widget and history
+    let mut fed_by: HashMap<Metric, &str> = HashMap::new();
+    for source in &file.sources {
+        let metrics = match &source.handler {
+            Some(handler) => handler.metrics(),
+            None => source.fields.iter().map(|field| field.target.metric()).collect(),
+        };
+        for metric in metrics {
+            match fed_by.insert(metric, &source.topic) {
+                Some(other) if other != source.topic => {
+                    bail!("Sources {:?} and {:?} both feed {:?}, use one source per value", other, source.topic,
+                          metric);
+                },
+                _ => {},
+            }
+        }
+    }
+    Ok(file.sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(path: &str, scale: f32, offset: f32, unit: Option<Unit>) -> FieldMapping {
+        FieldMapping { path: Selector::parse(path).unwrap(), target: Target::IndoorT, scale, offset, unit }
+    }
+
+    #[test]
+    fn extract_numbers_and_strings() {
+        let payload = json::parse(r#"{"t": 21.5, "s": " 23.4 ", "text": "online", "f": 212}"#).unwrap();
+        assert_eq!(field("t", 1.0, 0.0, None).extract(&payload), Some(21.5));
+        assert_eq!(field("s", 1.0, 0.0, None).extract(&payload), Some(23.4));
+        assert_eq!(field("text", 1.0, 0.0, None).extract(&payload), None);
+        assert_eq!(field("missing", 1.0, 0.0, None).extract(&payload), None);
+        assert_eq!(field("t", 2.0, -1.0, None).extract(&payload), Some(42.0));
+        assert_eq!(field("f", 1.0, 0.0, Some(Unit::Fahrenheit)).extract(&payload), Some(100.0));
+    }
+
+    fn load(name: &str, content: &str) -> Result<Vec<SourceConfig>> {
+        let path = std::env::temp_dir().join(format!("sources_test_{}_{}.toml", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let sources = load_sources(&path.to_string_lossy());
+        let _ = fs::remove_file(&path);
+        sources
+    }
+
+    #[test]
+    fn load_example() {
+        let sources = load("example", include_str!("../sources.example.toml")).unwrap();
+        assert!(!sources.is_empty());
+    }
+
+    #[test]
+    fn duplicate_targets_are_rejected() {
+        let two_sources = r#"
+            [[source]]
+            topic = "a"
+            [[source.field]]
+            path = "t"
+            target = "indoor_t"
+            [[source]]
+            topic = "b"
+            [[source.field]]
+            path = "t"
+            target = "indoor_t"
+        "#;
+        assert!(load("two_sources", two_sources).is_err());
+        let handler_and_field = r#"
+            [[source]]
+            topic = "a"
+            handler = "mhz19"
+            [[source]]
+            topic = "b"
+            [[source.field]]
+            path = "co2"
+            target = "indoor_co2"
+        "#;
+        assert!(load("handler_and_field", handler_and_field).is_err());
+        let same_source = r#"
+            [[source]]
+            topic = "a"
+            [[source.field]]
+            path = "t1"
+            target = "indoor_t"
+            [[source.field]]
+            path = "t2"
+            target = "indoor_t"
+        "#;
+        assert!(load("same_source", same_source).is_err());
+        // both are parts of one forecast
+        let forecasts = r#"
+            [[source]]
+            topic = "a"
+            [[source.field]]
+            path = "kp_3h"
+            target = "kp_forecast_3h"
+            [[source.field]]
+            path = "kp_24h"
+            target = "kp_forecast_24h"
+        "#;
+        assert_eq!(load("forecasts", forecasts).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn extract_rejects_not_finite() {
+        let payload = json::parse(r#"{"values": ["NaN", "inf", "-infinity", "1e39"], "big": 1e300}"#).unwrap();
+        for path in ["values[0]", "values[1]", "values[2]", "values[3]", "big"] {
+            assert_eq!(field(path, 1.0, 0.0, None).extract(&payload), None, "{:?} should be rejected", path);
+        }
+        // overflow after scaling
+        let payload = json::parse(r#"{"t": 3e38}"#).unwrap();
+        assert_eq!(field("t", 10.0, 0.0, None).extract(&payload), None);
+    }
+}
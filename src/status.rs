@@ -0,0 +1,83 @@
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use chrono::Utc;
+use json::{object, JsonValue};
+use crate::Config;
+use crate::connector::{MQTTPublisher, PayloadErrors};
+use crate::model::Model;
+use crate::video::{VideoPlayer, VideoStats};
+
+// payloads of the availability topic
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+pub fn availability_topic(config: &Config) -> String {
+    config.mqtt_display_topic.clone() + "/availability"
+}
+
+pub fn telemetry_topic(config: &Config) -> String {
+    config.mqtt_display_topic.clone() + "/telemetry"
+}
+
+// Publishes state of the display, e.g.
+//  {"uptime_s": 3600, "video_state": "playing", "video_fps": 9.8, "video_skipped_frames": 0,
+//   "video_recording": false,
+//   "source_age_s": {"homeassistant/sensor/cubieboard_htu21d/state": 12, ...},
+//   "payload_errors": {"some/topic": 1}}
+pub struct StatusReporter {
+    publisher: MQTTPublisher,
+    topic: String,
+    payload_errors: PayloadErrors,
+    video: VideoPlayer,
+    video_stats: VideoStats,
+    started: Instant,
+    // time and number of video frames of the previous report
+    last_report: (Instant, u64)
+}
+
+impl StatusReporter {
+    pub fn new(config: &Config, publisher: MQTTPublisher, payload_errors: PayloadErrors, video: VideoPlayer,
+               video_stats: VideoStats) -> Self {
+        let now = Instant::now();
+        Self {
+            publisher,
+            topic: telemetry_topic(config),
+            payload_errors,
+            video,
+            video_stats,
+            started: now,
+            last_report: (now, 0)
+        }
+    }
+
+    pub fn publish_telemetry(&mut self, model: &Model) {
+        let now = Instant::now();
+        let frames = self.video_stats.frames.load(Ordering::Relaxed);
+        let elapsed = now.duration_since(self.last_report.0).as_secs_f64();
+        let fps = if elapsed > 0.0 { (frames - self.last_report.1) as f64 / elapsed } else { 0.0 };
+        self.last_report = (now, frames);
+
+        let mut source_age_s = JsonValue::new_object();
+        for (topic, source) in &model.data_view_map {
+            source_age_s[topic.as_str()] = match source.last_received {
+                Some(last_received) => (Utc::now() - last_received).num_seconds().into(),
+                None => JsonValue::Null,
+            };
+        }
+        let mut payload_errors = JsonValue::new_object();
+        for (topic, errors) in self.payload_errors.lock().expect("Couldn't lock MQTT payload errors").iter() {
+            payload_errors[topic.as_str()] = (*errors).into();
+        }
+
+        let telemetry = object! {
+            uptime_s: now.duration_since(self.started).as_secs(),
+            video_state: self.video.state_name(),
+            video_fps: (fps * 10.0).round() / 10.0,
+            video_skipped_frames: self.video_stats.skipped.load(Ordering::Relaxed),
+            video_recording: self.video.is_recording(),
+            source_age_s: source_age_s,
+            payload_errors: payload_errors
+        };
+        self.publisher.publish(&self.topic, telemetry.dump(), false);
+    }
+}
@@ -0,0 +1,169 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+use crate::database::Bucket;
+
+
+pub type StoredSample = (DateTime<Utc>, f32);
+
+pub enum StoredRecord {
+    Sample(StoredSample),
+    // downsampled data: tier index, bucket start and statistics
+    Bucket(usize, DateTime<Utc>, Bucket)
+}
+
+// Append-only text file with one record per line:
+//  raw sample "<RFC 3339 timestamp>,<value>"
+//  downsampled bucket "B<tier>,<RFC 3339 start>,<count>,<sum>,<min>,<max>,<last>"
+pub struct HistoryStorage {
+    path: PathBuf,
+    appended: usize
+}
+
+impl HistoryStorage {
+    pub fn open(dir: &str, name: &str) -> Option<Self> {
+        if dir.is_empty() {
+            return None;
+        }
+        if let Err(e) = fs::create_dir_all(dir) {
+            println!("Storage: couldn't create data dir {:?}: {:?}", dir, e);
+            return None;
+        }
+        Some(Self {
+            path: Path::new(dir).join(name.to_owned() + ".csv"),
+            appended: 0
+        })
+    }
+
+    // Number of records appended since the file was last rewritten
+    pub fn appended(&self) -> usize {
+        self.appended
+    }
+
+    pub fn load(&self) -> Vec<StoredRecord> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(_) => return Vec::new(),
+        };
+        let mut records = Vec::new();
+        for line in BufReader::new(file).lines().map_while(|line| line.ok()) {
+            match parse_record(&line) {
+                Some(record) => records.push(record),
+                None => println!("Storage: skipping invalid record {:?} in {:?}", line, self.path),
+            }
+        }
+        records
+    }
+
+    pub fn append(&mut self, record: &StoredRecord) {
+        let result = OpenOptions::new().create(true).append(true).open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", format_record(record)));
+        match result {
+            Ok(_) => self.appended += 1,
+            Err(e) => println!("Storage: couldn't append to {:?}: {:?}", self.path, e),
+        }
+    }
+
+    // Replaces the file content, written to a temporary file first so a power cut can't lose the history
+    pub fn rewrite(&mut self, records: &[StoredRecord]) {
+        let tmp_path = self.path.with_extension("csv.tmp");
+        let result = File::create(&tmp_path)
+            .and_then(|mut file| {
+                for record in records {
+                    writeln!(file, "{}", format_record(record))?;
+                }
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp_path, &self.path));
+        match result {
+            Ok(_) => self.appended = 0,
+            Err(e) => println!("Storage: couldn't rewrite {:?}: {:?}", self.path, e),
+        }
+    }
+}
+
+fn format_record(record: &StoredRecord) -> String {
+    match record {
+        StoredRecord::Sample((timestamp, value)) => format!("{},{}", timestamp.to_rfc3339(), value),
+        StoredRecord::Bucket(tier, start, bucket) => format!("B{},{},{},{},{},{},{}", tier, start.to_rfc3339(),
+                                                             bucket.count, bucket.sum, bucket.min, bucket.max,
+                                                             bucket.last),
+    }
+}
+
+fn parse_record(line: &str) -> Option<StoredRecord> {
+    let mut fields = line.split(',').map(|field| field.trim());
+    let first = fields.next()?;
+    match first.strip_prefix('B') {
+        Some(tier) => {
+            let tier = tier.parse().ok()?;
+            let start = parse_timestamp(fields.next()?)?;
+            let bucket = Bucket {
+                count: fields.next()?.parse().ok()?,
+                sum: fields.next()?.parse().ok()?,
+                min: fields.next()?.parse().ok()?,
+                max: fields.next()?.parse().ok()?,
+                last: fields.next()?.parse().ok()?
+            };
+            Some(StoredRecord::Bucket(tier, start, bucket))
+        },
+        None => {
+            let timestamp = parse_timestamp(first)?;
+            let value = fields.next()?.parse().ok()?;
+            Some(StoredRecord::Sample((timestamp, value)))
+        },
+    }
+}
+
+fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    Some(DateTime::parse_from_rfc3339(timestamp).ok()?.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_round_trip() {
+        let line = "2024-05-01T12:00:00+00:00,21.5";
+        match parse_record(line) {
+            Some(StoredRecord::Sample((timestamp, value))) => {
+                assert_eq!(timestamp, DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap());
+                assert_eq!(value, 21.5);
+            },
+            _ => panic!("{:?} should be a sample", line),
+        }
+        assert_eq!(format_record(&parse_record(line).unwrap()), line);
+    }
+
+    #[test]
+    fn bucket_round_trip() {
+        let line = "B1,2024-05-01T12:00:00+00:00,4,86.2,-0.5,30.25,21";
+        match parse_record(line) {
+            Some(StoredRecord::Bucket(tier, start, bucket)) => {
+                assert_eq!(tier, 1);
+                assert_eq!(start, DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z").unwrap());
+                assert_eq!((bucket.count, bucket.sum, bucket.min, bucket.max, bucket.last),
+                           (4, 86.2, -0.5, 30.25, 21.0));
+            },
+            _ => panic!("{:?} should be a bucket", line),
+        }
+        assert_eq!(format_record(&parse_record(line).unwrap()), line);
+    }
+
+    #[test]
+    fn other_timezone_and_spaces() {
+        let record = parse_record(" 2024-05-01T15:00:00+03:00 , 1.25 ").unwrap();
+        assert_eq!(format_record(&record), "2024-05-01T12:00:00+00:00,1.25");
+    }
+
+    #[test]
+    fn invalid_records() {
+        for line in ["", "garbage", "2024-05-01T12:00:00+00:00", "2024-05-01T12:00:00+00:00,x", "2024-05-01,1",
+                     "B,2024-05-01T12:00:00+00:00,1,1,1,1,1", "Bx,2024-05-01T12:00:00+00:00,1,1,1,1,1",
+                     "B0,2024-05-01T12:00:00+00:00,1,1,1,1"] {
+            assert!(parse_record(line).is_none(), "{:?} should be invalid", line);
+        }
+    }
+}
@@ -1,9 +1,19 @@
 use slint::*;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use crate::database::HistoryValue;
+use crate::model::Metric;
+use crate::connector::ConnectionState;
+use crate::video::VideoOverlays;
 
 slint::include_modules!();
 
 pub type KpIndexUI = KpIndex;
+pub type HistoryPointUI = HistoryPoint;
+pub type DataStateUI = DataState;
+pub type StreamStatsUI = StreamStats;
 
 #[derive(Clone)]
 pub struct WindowUpdater {
@@ -16,21 +26,54 @@ impl WindowUpdater {
     }
 
     // gui element updaters
-    pub fn update_indoor_t(&self, value: i32) {
+    pub fn update_indoor_t(&self, value: f32) {
         self.window_weak.upgrade_in_event_loop(move |window| {
             window.global::<IndoorAdapter>().set_current_temp(value);
         }).unwrap();
     }
-    pub fn update_indoor_rh(&self, value: i32) {
+    pub fn update_indoor_rh(&self, value: f32) {
         self.window_weak.upgrade_in_event_loop(move |window| {
             window.global::<IndoorAdapter>().set_current_rh(value);
         }).unwrap();
     }
-    pub fn update_indoor_co2(&self, value: i32) {
+    pub fn update_indoor_co2(&self, value: f32) {
         self.window_weak.upgrade_in_event_loop(move |window| {
             window.global::<IndoorAdapter>().set_current_co2(value);
         }).unwrap();
     }
+    pub fn update_metric_state(&self, metric: Metric, state: DataStateUI) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let indoor = window.global::<IndoorAdapter>();
+            let space_weather = window.global::<SpaceWeatherAdapter>();
+            match metric {
+                Metric::IndoorT => indoor.set_temp_state(state),
+                Metric::IndoorRh => indoor.set_rh_state(state),
+                Metric::IndoorCo2 => indoor.set_co2_state(state),
+                Metric::KpHistory => space_weather.set_kp_history_state(state),
+                Metric::Kp => space_weather.set_kp_state(state),
+                Metric::KpForecast => space_weather.set_kp_forecast_state(state),
+                Metric::SolarRadiation => space_weather.set_solar_radiation_state(state),
+                Metric::SolarRadiationForecast => space_weather.set_solar_radiation_forecast_state(state),
+            }
+        }).unwrap();
+    }
+    pub fn update_metric_age(&self, metric: Metric, age: String) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let indoor = window.global::<IndoorAdapter>();
+            let space_weather = window.global::<SpaceWeatherAdapter>();
+            let age = SharedString::from(age);
+            match metric {
+                Metric::IndoorT => indoor.set_temp_updated(age),
+                Metric::IndoorRh => indoor.set_rh_updated(age),
+                Metric::IndoorCo2 => indoor.set_co2_updated(age),
+                Metric::KpHistory => space_weather.set_kp_history_updated(age),
+                Metric::Kp => space_weather.set_kp_updated(age),
+                Metric::KpForecast => space_weather.set_kp_forecast_updated(age),
+                Metric::SolarRadiation => space_weather.set_solar_radiation_updated(age),
+                Metric::SolarRadiationForecast => space_weather.set_solar_radiation_forecast_updated(age),
+            }
+        }).unwrap();
+    }
     pub fn update_solar_radiation_now(&self, value: f32) {
         self.window_weak.upgrade_in_event_loop(move |window| {
             window.global::<SpaceWeatherAdapter>().set_solar_radiation_now(value);
@@ -75,28 +118,159 @@ impl WindowUpdater {
             current_kps.set_row_data(current_kps.row_count() - 1, value);
         }).unwrap();
     }
-    pub fn update_video_frame(&self, data: slint::SharedPixelBuffer<slint::Rgb8Pixel>) {
+    // `pending` is cleared when the frame is taken
+    pub fn update_video_frame(&self, data: slint::SharedPixelBuffer<slint::Rgb8Pixel>, pending: Arc<AtomicBool>) {
         self.window_weak.upgrade_in_event_loop(move |window| {
             window.global::<VideoAdapter>().set_video_frame(slint::Image::from_rgb8(data));
+            pending.store(false, Ordering::Release);
+        }).unwrap();
+    }
+    pub fn update_indoor_precision(&self, temp: i32, rh: i32, co2: i32) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<IndoorAdapter>().set_temp_precision(temp);
+            window.global::<IndoorAdapter>().set_rh_precision(rh);
+            window.global::<IndoorAdapter>().set_co2_precision(co2);
+        }).unwrap();
+    }
+    pub fn update_mqtt_state(&self, state: ConnectionState, timezone_offset_h: i8) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let status = window.global::<StatusAdapter>();
+            match state {
+                ConnectionState::Connecting => status.set_mqtt_state(MqttState::Connecting),
+                ConnectionState::Connected => status.set_mqtt_state(MqttState::Connected),
+                ConnectionState::Disconnected(since) => {
+                    let since = since + chrono::Duration::hours(timezone_offset_h.into());
+                    status.set_mqtt_disconnected_since(since.format("%H:%M").to_string().into());
+                    status.set_mqtt_state(MqttState::Disconnected);
+                },
+            }
+        }).unwrap();
+    }
+    // Display control
+    pub fn update_day_skin(&self, day: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<Skin>().set_day(day);
+        }).unwrap();
+    }
+    pub fn update_video_playing(&self, playing: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_playing(playing);
+        }).unwrap();
+    }
+    pub fn update_video_source(&self, name: String) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_source_name(name.into());
+        }).unwrap();
+    }
+    pub fn update_video_source_count(&self, count: usize) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_source_count(count as i32);
+        }).unwrap();
+    }
+    pub fn update_video_recording(&self, recording: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_recording(recording);
+        }).unwrap();
+    }
+    pub fn update_video_recording_enabled(&self, enabled: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_recording_enabled(enabled);
+        }).unwrap();
+    }
+    pub fn update_video_motion(&self, motion: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_motion(motion);
+        }).unwrap();
+    }
+    pub fn update_video_playback(&self, playback: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_playback(playback);
+        }).unwrap();
+    }
+    pub fn update_video_stats(&self, stats: StreamStatsUI) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_stats(stats);
+        }).unwrap();
+    }
+    pub fn update_video_time(&self, time: String) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_time(time.into());
+        }).unwrap();
+    }
+    pub fn update_video_overlays(&self, overlays: VideoOverlays) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let video = window.global::<VideoAdapter>();
+            video.set_show_time(overlays.time);
+            video.set_show_source(overlays.source);
+            video.set_show_stats(overlays.stats);
+            video.set_show_badge(overlays.badge);
+        }).unwrap();
+    }
+    pub fn update_video_reconnecting(&self, reconnecting: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<VideoAdapter>().set_reconnecting(reconnecting);
+        }).unwrap();
+    }
+    // 0 - no dimming, 1 - black screen
+    pub fn update_dim(&self, dim: f32) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<DisplayAdapter>().set_dim(dim);
+        }).unwrap();
+    }
+    pub fn update_blank(&self, blank: bool) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            window.global::<DisplayAdapter>().set_blank(blank);
+        }).unwrap();
+    }
+    pub fn show_message(&self, text: String, timeout: Duration) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let display = window.global::<DisplayAdapter>();
+            display.set_message(text.clone().into());
+            display.set_message_shown(true);
+            let window_weak = window.as_weak();
+            Timer::single_shot(timeout, move || {
+                if let Some(window) = window_weak.upgrade() {
+                    let display = window.global::<DisplayAdapter>();
+                    // a newer message stays shown for its own time
+                    if display.get_message() == text.as_str() {
+                        display.set_message_shown(false);
+                    }
+                }
+            });
         }).unwrap();
     }
     // History graphs
-    pub fn update_indoor_history_t(&self, data: Vec<i32>) {
+    pub fn update_indoor_history_range(&self, label: &str) {
+        let label = SharedString::from(label);
         self.window_weak.upgrade_in_event_loop(move |window| {
-            let chart_data = VecModel::from(data);
+            window.global::<IndoorAdapter>().set_history_range(label);
+        }).unwrap();
+    }
+    pub fn update_indoor_history_t(&self, data: Vec<Option<HistoryValue>>) {
+        self.window_weak.upgrade_in_event_loop(move |window| {
+            let chart_data = VecModel::from(to_history_points(data));
             window.global::<IndoorAdapter>().set_history_temp(Rc::new(chart_data).into());
         }).unwrap();
     }
-    pub fn update_indoor_history_rh(&self, data: Vec<i32>) {
+    pub fn update_indoor_history_rh(&self, data: Vec<Option<HistoryValue>>) {
         self.window_weak.upgrade_in_event_loop(move |window| {
-            let chart_data = VecModel::from(data);
+            let chart_data = VecModel::from(to_history_points(data));
             window.global::<IndoorAdapter>().set_history_rh(Rc::new(chart_data).into());
         }).unwrap();
     }
-    pub fn update_indoor_history_co2(&self, data: Vec<i32>) {
+    pub fn update_indoor_history_co2(&self, data: Vec<Option<HistoryValue>>) {
         self.window_weak.upgrade_in_event_loop(move |window| {
-            let chart_data = VecModel::from(data);
+            let chart_data = VecModel::from(to_history_points(data));
             window.global::<IndoorAdapter>().set_history_co2(Rc::new(chart_data).into());
         }).unwrap();
     }
 }
+
+fn to_history_points(data: Vec<Option<HistoryValue>>) -> Vec<HistoryPointUI> {
+    data.into_iter()
+        .map(|point| match point {
+            Some(point) => HistoryPointUI { value: point.value, min: point.min, max: point.max, valid: true },
+            None => HistoryPointUI::default(),
+        })
+        .collect()
+}
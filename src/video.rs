@@ -3,24 +3,466 @@
 
 slint::include_modules!();
 
-use anyhow::{bail, Result};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use anyhow::{anyhow, bail, Result};
 
 use gst::prelude::*;
 
-use crate::ui::WindowUpdater;
+use crate::recorder::{self, Recorder, RecordingConfig};
+use crate::ui::{StreamStatsUI, WindowUpdater};
 
-fn try_gstreamer_video_frame_to_pixel_buffer(
-    frame: &gst_video::VideoFrame<gst_video::video_frame::Readable>,
-) -> Result<slint::SharedPixelBuffer<slint::Rgb8Pixel>> {
+// used until the size of the video widget is known
+pub const DEFAULT_FRAME_SIZE: (u32, u32) = (540, 304);
+
+// delays between pipeline restarts after failures
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// one buffer is shown, one is waiting for the UI and one is being filled
+const FRAME_POOL_SIZE: usize = 3;
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+const RECORDER_ERROR_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Counters shared with the status telemetry
+#[derive(Clone, Default)]
+pub struct VideoStats {
+    // frames passed to the UI
+    pub frames: Arc<AtomicU64>,
+    // frames dropped because the UI didn't take the previous one yet
+    pub skipped: Arc<AtomicU64>
+}
+
+#[derive(Debug, Clone)]
+pub struct VideoSource {
+    // shown in the video title, can be empty
+    pub name: String,
+    pub uri: String
+}
+
+// List of sources in form "Garden=rtsp://192.168.1.10/stream;Street=https://...", names are optional
+#[derive(Debug, Clone, Default)]
+pub struct VideoSources(pub Vec<VideoSource>);
+
+impl FromStr for VideoSources {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sources = Vec::new();
+        for entry in s.split(';').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let source = match entry.split_once('=') {
+                // "=" in the uri query isn't a name separator
+                Some((name, uri)) if !name.contains("://") => VideoSource { name: name.trim().to_owned(),
+                                                                            uri: uri.trim().to_owned() },
+                _ => VideoSource { name: String::new(), uri: entry.to_owned() },
+            };
+            if source.uri.is_empty() {
+                return Err(format!("No uri of video source '{}'", entry));
+            }
+            sources.push(source);
+        }
+        Ok(VideoSources(sources))
+    }
+}
+
+// Overlays over the video in form "time,source,stats,badge", empty value disables all of them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VideoOverlays {
+    pub time: bool,
+    pub source: bool,
+    pub stats: bool,
+    pub badge: bool
+}
+
+impl FromStr for VideoOverlays {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut overlays = VideoOverlays::default();
+        for overlay in s.split(',').map(|overlay| overlay.trim()).filter(|overlay| !overlay.is_empty()) {
+            match overlay.to_lowercase().as_str() {
+                "time" => overlays.time = true,
+                "source" => overlays.source = true,
+                "stats" => overlays.stats = true,
+                "badge" => overlays.badge = true,
+                _ => return Err(format!("Unknown video overlay '{}'", overlay)),
+            }
+        }
+        Ok(overlays)
+    }
+}
+
+// Called for every frame passed to the UI, `discontinuity` is set for the first frame after a source change
+pub type FrameObserver = Box<dyn FnMut(&slint::SharedPixelBuffer<slint::Rgb8Pixel>, bool) + Send>;
+
+// Consumers of the frames passed to the UI, shared by the rebuilt pipelines
+#[derive(Clone, Default)]
+struct FrameTaps {
+    last_frame: Arc<Mutex<Option<slint::SharedPixelBuffer<slint::Rgb8Pixel>>>>,
+    observer: Arc<Mutex<Option<FrameObserver>>>,
+    discontinuity: Arc<AtomicBool>
+}
+
+impl FrameTaps {
+    fn push(&self, frame: &slint::SharedPixelBuffer<slint::Rgb8Pixel>) {
+        *self.last_frame.lock().expect("Couldn't lock last video frame") = Some(frame.clone());
+        if let Some(observer) = self.observer.lock().expect("Couldn't lock video frame observer").as_mut() {
+            observer(frame, self.discontinuity.swap(false, Ordering::Relaxed));
+        }
+    }
+}
+
+// Job executed on the video control thread
+type ControlJob = Box<dyn FnOnce(&VideoPlayer) + Send>;
+
+// Handle to control the pipeline, it stays valid when the pipeline is rebuilt after failures.
+// State changes of the pipeline can take seconds for network sources, they are serialized by `transition` and
+// made without the lock of the player state, so the UI thread doesn't wait for them.
+#[derive(Clone)]
+pub struct VideoPlayer {
+    state: Arc<Mutex<PlayerState>>,
+    transition: Arc<Mutex<()>>,
+    control: mpsc::Sender<ControlJob>
+}
+
+struct PlayerState {
+    pipeline: gst::Pipeline,
+    source: gst::Element,
+    tee: gst::Element,
+    appsink: gst_app::AppSink,
+    uri: String,
+    // a recorded segment is shown
+    playback: bool,
+    sources: Vec<VideoSource>,
+    // index of the shown source
+    current: usize,
+    // requested by user
+    playing: bool,
+    // max size of frames in physical pixels
+    frame_size: (u32, u32),
+    max_rate: u8,
+    updater: WindowUpdater,
+    stats: VideoStats,
+    taps: FrameTaps,
+    // None if recording is disabled
+    recording: Option<RecordingConfig>,
+    // requested by user
+    record: bool,
+    recorder: Option<Recorder>,
+    // detached branches finishing their segments
+    stopping_recorders: Vec<gst::Bin>
+}
+
+impl PlayerState {
+    fn target_state(&self) -> gst::State {
+        if self.playing { gst::State::Playing } else { gst::State::Paused }
+    }
+
+    // Attaches or detaches the recording branch, recorded segments aren't recorded again
+    fn update_recorder(&mut self) {
+        let config = match &self.recording {
+            Some(config) => config,
+            None => return,
+        };
+        let record = self.record && !self.playback;
+        if record && self.recorder.is_none() {
+            match Recorder::attach(&self.pipeline, &self.tee, config) {
+                Ok(recorder) => self.recorder = Some(recorder),
+                Err(e) => {
+                    println!("Recorder: couldn't start: {:?}", e);
+                    self.record = false;
+                },
+            }
+        } else if !record {
+            if let Some(recorder) = self.recorder.take() {
+                self.stopping_recorders.push(recorder.detach(&self.tee));
+            }
+        }
+        self.updater.update_video_recording(self.record);
+    }
+}
+
+impl VideoPlayer {
+    fn lock(&self) -> std::sync::MutexGuard<'_, PlayerState> {
+        self.state.lock().expect("Couldn't lock video player")
+    }
+    fn begin_transition(&self) -> std::sync::MutexGuard<'_, ()> {
+        self.transition.lock().expect("Couldn't lock video transition")
+    }
+    // Shows the uri, `playback` is set for recorded segments
+    fn load_uri(&self, video_uri: &str, playback: bool) -> Result<()> {
+        let _transition = self.begin_transition();
+        let (pipeline, source, stopping_recorders, discontinuity, target) = {
+            let mut state = self.lock();
+            let name = state.sources.iter().find(|source| source.uri == video_uri)
+                .map(|source| source.name.clone()).unwrap_or_default();
+            state.updater.update_video_source(name);
+            state.uri = video_uri.to_owned();
+            state.playback = playback;
+            state.updater.update_video_playback(playback);
+            state.update_recorder();
+            (state.pipeline.clone(), state.source.clone(), std::mem::take(&mut state.stopping_recorders),
+             state.taps.discontinuity.clone(), state.target_state())
+        };
+        pipeline.set_state(gst::State::Null)?;
+        // segments of the stopped branches are closed with the pipeline
+        for bin in stopping_recorders {
+            Recorder::remove(&pipeline, &bin);
+        }
+        source.set_property_from_str("uri", video_uri);
+        discontinuity.store(true, Ordering::Relaxed);
+        pipeline.set_state(target)?;
+        Ok(())
+    }
+    // Runs the job on the video control thread. Restart of the pipeline for another uri can take seconds for
+    // network sources, the MQTT and UI threads don't wait for it.
+    pub fn control(&self, job: impl FnOnce(&VideoPlayer) + Send + 'static) {
+        if self.control.send(Box::new(job)).is_err() {
+            println!("Video: control thread is gone");
+        }
+    }
+    pub fn set_playing(&self, playing: bool) -> Result<()> {
+        let _transition = self.begin_transition();
+        let (pipeline, target) = {
+            let mut state = self.lock();
+            state.playing = playing;
+            (state.pipeline.clone(), state.target_state())
+        };
+        pipeline.set_state(target)?;
+        Ok(())
+    }
+    pub fn is_playing(&self) -> bool {
+        self.lock().playing
+    }
+    pub fn toggle_pause_play(&self) -> Result<()> {
+        self.set_playing(!self.is_playing())
+    }
+    pub fn set_uri(&self, video_uri: &str) -> Result<()> {
+        self.load_uri(video_uri, false)
+    }
+    pub fn set_recording(&self, record: bool) -> Result<()> {
+        let mut state = self.lock();
+        if state.recording.is_none() {
+            bail!("recording is disabled");
+        }
+        state.record = record;
+        state.update_recorder();
+        Ok(())
+    }
+    pub fn is_recording(&self) -> bool {
+        self.lock().record
+    }
+    pub fn toggle_recording(&self) -> Result<()> {
+        self.set_recording(!self.is_recording())
+    }
+    // File names of recorded segments from the oldest one
+    pub fn recordings(&self) -> Vec<String> {
+        match &self.lock().recording {
+            Some(config) => recorder::recordings(&config.dir).iter()
+                .filter_map(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    // Shows a recorded segment (the latest one if no name is given), live video returns after its end
+    pub fn play_recording(&self, name: Option<&str>) -> Result<String> {
+        let dir = match &self.lock().recording {
+            Some(config) => config.dir.clone(),
+            None => bail!("recording is disabled"),
+        };
+        let segments = recorder::recordings(&dir);
+        let path = match name {
+            Some(name) => segments.into_iter().find(|path| path.file_name() == Some(name.as_ref())),
+            None => segments.into_iter().last(),
+        }.ok_or_else(|| anyhow!("no such recording"))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let uri = gst::glib::filename_to_uri(path.canonicalize()?, None)?;
+        self.load_uri(&uri, true)?;
+        self.lock().updater.update_video_source(format!("recording {}", name));
+        Ok(name)
+    }
+    // Returns to the live source after playback of a recording
+    fn stop_playback(&self) -> Result<()> {
+        let uri = {
+            let state = self.lock();
+            state.sources[state.current].uri.clone()
+        };
+        self.set_uri(&uri)
+    }
+    fn is_playback(&self) -> bool {
+        self.lock().playback
+    }
+    // Removes detached recording branches when their segments are closed
+    fn on_segment_closed(&self, message: &gst::Message) {
+        let src = match message.src() {
+            Some(src) => src.clone(),
+            None => return,
+        };
+        let (pipeline, closed, recording) = {
+            let mut state = self.lock();
+            let (closed, stopping): (Vec<gst::Bin>, Vec<gst::Bin>) = state.stopping_recorders.drain(..)
+                .partition(|bin| src.has_as_ancestor(bin));
+            state.stopping_recorders = stopping;
+            (state.pipeline.clone(), closed, state.recording.clone())
+        };
+        for bin in closed {
+            Recorder::remove(&pipeline, &bin);
+        }
+        if let Some(config) = &recording {
+            recorder::apply_retention(config);
+        }
+    }
+    // Stops recording if the error comes from a recording branch, the live video keeps playing then.
+    // Returns false for errors of the video itself.
+    fn on_recorder_error(&self, src: &gst::Object, error: &str) -> bool {
+        let mut state = self.lock();
+        let pipeline = state.pipeline.clone();
+        if let Some(index) = state.stopping_recorders.iter().position(|bin| src.has_as_ancestor(bin)) {
+            let bin = state.stopping_recorders.remove(index);
+            drop(state);
+            println!("Recorder: error while finishing segment: {}", error);
+            Recorder::remove(&pipeline, &bin);
+            return true;
+        }
+        if !state.recorder.as_ref().map(|recorder| recorder.contains(src)).unwrap_or(false) {
+            return false;
+        }
+        println!("Recorder: error: {}", error);
+        let recorder = state.recorder.take().unwrap();
+        let tee = state.tee.clone();
+        state.record = false;
+        state.updater.update_video_recording(false);
+        state.updater.show_message(format!("Recording stopped: {}", error), RECORDER_ERROR_MESSAGE_TIMEOUT);
+        drop(state);
+        recorder.abort(&pipeline, &tee);
+        true
+    }
+    // Frames are scaled to fit the size, the pipeline renegotiates it on the fly
+    // Called by the UI timer, the size is set by the next call when the player is busy
+    pub fn set_frame_size(&self, width: u32, height: u32) {
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
+        if state.frame_size == (width, height) || width == 0 || height == 0 {
+            return;
+        }
+        println!("Video: frame size {}x{}", width, height);
+        state.frame_size = (width, height);
+        state.appsink.set_caps(Some(&frame_caps(width, height)));
+        // upstream event, it makes videoscale negotiate the new caps
+        if let Some(pad) = state.appsink.static_pad("sink") {
+            if !pad.push_event(gst::event::Reconfigure::new()) {
+                println!("Video: couldn't renegotiate frame size");
+            }
+        }
+    }
+    // Shows the next (step 1) or previous (step -1) source, only one source is decoded at a time
+    pub fn switch_source(&self, step: isize) -> Result<()> {
+        let uri = {
+            let mut state = self.lock();
+            if state.sources.len() < 2 {
+                return Ok(());
+            }
+            state.current = (state.current as isize + step).rem_euclid(state.sources.len() as isize) as usize;
+            state.sources[state.current].uri.clone()
+        };
+        self.set_uri(&uri)
+    }
+    pub fn set_frame_observer(&self, observer: FrameObserver) {
+        let observer_slot = self.lock().taps.observer.clone();
+        *observer_slot.lock().expect("Couldn't lock video frame observer") = Some(observer);
+    }
+    pub fn last_frame(&self) -> Option<slint::SharedPixelBuffer<slint::Rgb8Pixel>> {
+        let last_frame = self.lock().taps.last_frame.clone();
+        let frame = last_frame.lock().expect("Couldn't lock last video frame").clone();
+        frame
+    }
+    // e.g. "playing"
+    pub fn state_name(&self) -> String {
+        format!("{:?}", self.pipeline().current_state()).to_lowercase()
+    }
+    fn pipeline(&self) -> gst::Pipeline {
+        self.lock().pipeline.clone()
+    }
+    fn stop(&self) {
+        let _transition = self.begin_transition();
+        if let Err(e) = self.pipeline().set_state(gst::State::Null) {
+            println!("Video: couldn't stop pipeline: {:?}", e);
+        }
+    }
+    // Replaces the pipeline with a new one in the requested state
+    fn rebuild(&self) {
+        let _transition = self.begin_transition();
+        // the old one could be started by the user while waiting for the restart
+        if let Err(e) = self.pipeline().set_state(gst::State::Null) {
+            println!("Video: couldn't stop pipeline: {:?}", e);
+        }
+        let mut state = self.lock();
+        let (pipeline, source, tee, appsink) = build_pipeline(&state.uri, state.frame_size, state.max_rate,
+                                                              state.updater.clone(), state.stats.clone(),
+                                                              state.taps.clone());
+        state.taps.discontinuity.store(true, Ordering::Relaxed);
+        state.pipeline = pipeline;
+        state.source = source;
+        state.tee = tee;
+        state.appsink = appsink;
+        // branches of the old pipeline are gone with it
+        state.recorder = None;
+        state.stopping_recorders.clear();
+        state.update_recorder();
+        let (pipeline, target) = (state.pipeline.clone(), state.target_state());
+        drop(state);
+        if let Err(e) = pipeline.set_state(target) {
+            println!("Video: couldn't start pipeline: {:?}", e);
+        }
+    }
+}
+
+// Pixel buffers are reused for the frames, a buffer is written in place when the UI doesn't show it anymore
+struct FramePool {
+    buffers: Vec<slint::SharedPixelBuffer<slint::Rgb8Pixel>>,
+    next: usize
+}
+
+impl FramePool {
+    fn new() -> Self {
+        Self { buffers: Vec::with_capacity(FRAME_POOL_SIZE), next: 0 }
+    }
+
+    fn buffer(&mut self, width: u32, height: u32) -> &mut slint::SharedPixelBuffer<slint::Rgb8Pixel> {
+        if self.buffers.len() < FRAME_POOL_SIZE {
+            self.buffers.push(slint::SharedPixelBuffer::new(width, height));
+            return self.buffers.last_mut().unwrap();
+        }
+        self.next = (self.next + 1) % FRAME_POOL_SIZE;
+        let buffer = &mut self.buffers[self.next];
+        if buffer.width() != width || buffer.height() != height {
+            *buffer = slint::SharedPixelBuffer::new(width, height);
+        }
+        buffer
+    }
+}
+
+// Copies the frame row by row skipping the stride padding, RGBx pixels lose the padding byte
+fn copy_frame(frame: &gst_video::VideoFrameRef<&gst::BufferRef>, pixels: &mut [u8]) -> Result<()> {
+    let width = frame.width() as usize;
+    let stride = frame.plane_stride()[0] as usize;
+    let rows = pixels.chunks_exact_mut(width * 3).zip(frame.plane_data(0)?.chunks(stride));
     match frame.format() {
         gst_video::VideoFormat::Rgb => {
-            let mut slint_pixel_buffer =
-                slint::SharedPixelBuffer::<slint::Rgb8Pixel>::new(frame.width(), frame.height());
-            frame
-                .buffer()
-                .copy_to_slice(0, slint_pixel_buffer.make_mut_bytes())
-                .expect("Unable to copy to slice!"); // Copies!
-            Ok(slint_pixel_buffer)
+            for (dst, src) in rows {
+                dst.copy_from_slice(&src[..width * 3]);
+            }
+        },
+        gst_video::VideoFormat::Rgbx => {
+            for (dst, src) in rows {
+                for (dst_pixel, src_pixel) in dst.chunks_exact_mut(3).zip(src.chunks_exact(4)) {
+                    dst_pixel.copy_from_slice(&src_pixel[..3]);
+                }
+            }
         },
         _ => {
             bail!(
@@ -29,16 +471,98 @@ fn try_gstreamer_video_frame_to_pixel_buffer(
             )
         },
     }
+    Ok(())
 }
 
-pub fn init_pipeline(video_uri: &String, width: u32, max_rate: u8, updater: WindowUpdater) {
+pub fn init_pipeline(sources: Vec<VideoSource>, frame_size: (u32, u32), max_rate: u8, stall_timeout: Duration,
+                     recording: Option<RecordingConfig>, updater: WindowUpdater, stats: VideoStats) -> VideoPlayer {
     println!("init video pipline ...");
     gst::init().unwrap();
 
+    let video_uri = sources[0].uri.clone();
+    updater.update_video_source(sources[0].name.clone());
+    let taps = FrameTaps::default();
+    let (pipeline, source, tee, appsink) = build_pipeline(&video_uri, frame_size, max_rate, updater.clone(),
+                                                          stats.clone(), taps.clone());
+    println!("init video pipline ... done");
+    println!("starting video pipline ...");
+    pipeline
+        .set_state(gst::State::Playing)
+        .expect("Unable to set the pipeline to the `Playing` state");
+    println!("starting video pipline ... OK");
+
+    let (control, jobs) = mpsc::channel::<ControlJob>();
+    let player = VideoPlayer {
+        transition: Arc::new(Mutex::new(())),
+        control,
+        state: Arc::new(Mutex::new(PlayerState {
+            pipeline,
+            source,
+            tee,
+            appsink,
+            uri: video_uri,
+            playback: false,
+            sources,
+            current: 0,
+            playing: true,
+            frame_size,
+            max_rate,
+            updater: updater.clone(),
+            stats: stats.clone(),
+            taps,
+            recording,
+            record: false,
+            recorder: None,
+            stopping_recorders: Vec::new()
+        }))
+    };
+    let supervised_player = player.clone();
+    thread::spawn(move || supervise(supervised_player, stall_timeout, updater, stats));
+    let controlled_player = player.clone();
+    thread::spawn(move || {
+        for job in jobs {
+            job(&controlled_player);
+        }
+    });
+    player
+}
+
+// Any size up to the given one with square pixels, videoscale keeps the aspect ratio of the stream
+fn frame_caps(width: u32, height: u32) -> gst::Caps {
+    gst_video::VideoCapsBuilder::new()
+        .format_list([gst_video::VideoFormat::Rgb, gst_video::VideoFormat::Rgbx])
+        .width_range(1..=width as i32)
+        .height_range(1..=height as i32)
+        .pixel_aspect_ratio(gst::Fraction::new(1, 1))
+        .build()
+}
+
+fn build_pipeline(video_uri: &str, frame_size: (u32, u32), max_rate: u8, updater: WindowUpdater,
+                  stats: VideoStats, taps: FrameTaps)
+                  -> (gst::Pipeline, gst::Element, gst::Element, gst_app::AppSink) {
     let pipeline = gst::Pipeline::with_name("test-pipeline");
 
-    let uridecodebin = gst::ElementFactory::make("uridecodebin")
-        .property_from_str("uri", &video_uri)
+    // the stream is demuxed and parsed but not decoded before the tee, so it's recorded without re-encoding
+    let source = gst::ElementFactory::make("urisourcebin")
+        .property_from_str("uri", video_uri)
+        .build()
+        .expect("Could not create gst element.");
+
+    let parsebin = gst::ElementFactory::make("parsebin")
+        .build()
+        .expect("Could not create gst element.");
+
+    // the recording branch is attached to it
+    let tee = gst::ElementFactory::make("tee")
+        .property("allow-not-linked", true)
+        .build()
+        .expect("Could not create gst element.");
+
+    let queue = gst::ElementFactory::make("queue")
+        .build()
+        .expect("Could not create gst element.");
+
+    let decodebin = gst::ElementFactory::make("decodebin")
         .build()
         .expect("Could not create gst element.");
 
@@ -56,18 +580,16 @@ pub fn init_pipeline(video_uri: &String, width: u32, max_rate: u8, updater: Wind
         .expect("Could not create gst element.");
 
     let appsink = gst_app::AppSink::builder()
-        .caps(
-            &gst_video::VideoCapsBuilder::new()
-                .format(gst_video::VideoFormat::Rgb)
-                .width(width as i32)
-                .pixel_aspect_ratio(gst::Fraction::new(1, 1))
-                .build(),
-        )
+        .caps(&frame_caps(frame_size.0, frame_size.1))
         .build();
 
     pipeline
         .add_many([
-            &uridecodebin,
+            &source,
+            &parsebin,
+            &tee,
+            &queue,
+            &decodebin,
             &videorate.upcast_ref(),
             &videoconvert.upcast_ref(),
             &videoscale.upcast_ref(),
@@ -75,55 +597,150 @@ pub fn init_pipeline(video_uri: &String, width: u32, max_rate: u8, updater: Wind
         ])
         .unwrap();
 
-    let sink_pad = videorate.static_pad("sink").unwrap();
-    uridecodebin.connect_pad_added(move |_, src_pad| {
-        // get pad info
-        let pad_caps = src_pad.current_caps().unwrap();
-        let pad_struct = pad_caps.structure(0).unwrap();
-        let pad_type = pad_struct.name();
-    
+    let parse_pad = parsebin.static_pad("sink").unwrap();
+    source.connect_pad_added(move |_, src_pad| {
+        // pads of the source can have no caps yet, the first one is the stream
+        if !parse_pad.is_linked() {
+            src_pad.link(&parse_pad).expect("Can't link urisourcebin with parsebin!");
+        }
+    });
+    let tee_pad = tee.static_pad("sink").unwrap();
+    parsebin.connect_pad_added(move |_, src_pad| {
         // skip not video pad
-        if pad_type.starts_with("video/") {
-            src_pad.link(&sink_pad).expect("Can't link uridecodebin with videorate!");
+        if is_video_pad(src_pad) && !tee_pad.is_linked() {
+            src_pad.link(&tee_pad).expect("Can't link parsebin with tee!");
+        }
+    });
+    let rate_pad = videorate.static_pad("sink").unwrap();
+    decodebin.connect_pad_added(move |_, src_pad| {
+        if is_video_pad(src_pad) && !rate_pad.is_linked() {
+            src_pad.link(&rate_pad).expect("Can't link decodebin with videorate!");
         }
     });
+    gst::Element::link_many([&tee, &queue, &decodebin]).expect("Many elements could not be linked.");
     gst::Element::link_many([&videorate, &videoconvert, &videoscale, &appsink.upcast_ref()])
         .expect("Many elements could not be linked.");
 
+    let mut pool = FramePool::new();
+    // set until the UI takes the frame
+    let frame_pending = Arc::new(AtomicBool::new(false));
     appsink.set_callbacks(
         gst_app::AppSinkCallbacks::builder()
             .new_sample(move |appsink| {
                 let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                if frame_pending.swap(true, Ordering::AcqRel) {
+                    stats.skipped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(gst::FlowSuccess::Ok);
+                }
 
-                let caps = sample.caps().unwrap().structure(0).unwrap();
-                let v_height = caps.get::<i32>("height").unwrap();
-                let v_width = caps.get::<i32>("width").unwrap();
-                // println!("height {}", v_height);
-                // println!("width {}", v_width);
-
-                let buffer = sample.buffer_owned().unwrap(); // Probably copies!
+                let video_info = sample.caps()
+                    .and_then(|caps| gst_video::VideoInfo::from_caps(caps).ok())
+                    .ok_or(gst::FlowError::NotNegotiated)?;
+                let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
+                let video_frame = gst_video::VideoFrameRef::from_buffer_ref_readable(buffer, &video_info)
+                    .map_err(|_| gst::FlowError::Error)?;
 
-                let video_info =
-                    gst_video::VideoInfo::builder(gst_video::VideoFormat::Rgb, v_width as u32, v_height as u32)
-                        .build()
-                        .expect("couldn't build video info!");
-                let video_frame = gst_video::VideoFrame::from_buffer_readable(buffer, &video_info).unwrap();
+                let slint_frame = pool.buffer(video_frame.width(), video_frame.height());
+                if let Err(e) = copy_frame(&video_frame, slint_frame.make_mut_bytes()) {
+                    println!("Unable to convert the video frame to a slint video frame: {:?}", e);
+                    return Err(gst::FlowError::NotSupported);
+                }
 
-                let slint_frame = try_gstreamer_video_frame_to_pixel_buffer(&video_frame)
-                    .expect("Unable to convert the video frame to a slint video frame!");
-
-                updater.update_video_frame(slint_frame);
+                taps.push(slint_frame);
+                updater.update_video_frame(slint_frame.clone(), frame_pending.clone());
+                stats.frames.fetch_add(1, Ordering::Relaxed);
 
                 Ok(gst::FlowSuccess::Ok)
             })
             .build(),
     );
 
-    println!("init video pipline ... done");
-    println!("starting video pipline ...");
-    pipeline
-        .set_state(gst::State::Playing)
-        .expect("Unable to set the pipeline to the `Playing` state");
+    (pipeline, source, tee, appsink)
+}
 
-    println!("starting video pipline ... OK");
+fn is_video_pad(pad: &gst::Pad) -> bool {
+    let caps = pad.current_caps().unwrap_or_else(|| pad.query_caps(None));
+    caps.structure(0).map(|structure| structure.name().starts_with("video/")) == Some(true)
+}
+
+// Keeps the UI in sync with the pipeline and restarts it after errors, end of stream or when no frames come
+// for `stall_timeout`
+fn supervise(player: VideoPlayer, stall_timeout: Duration, updater: WindowUpdater, stats: VideoStats) {
+    let mut frames = stats.frames.load(Ordering::Relaxed);
+    let mut last_progress = Instant::now();
+    let mut restart_delay = MIN_RESTART_DELAY;
+    let mut restarting = false;
+    // time and number of frames of the last stats report
+    let mut last_stats = (Instant::now(), frames);
+
+    loop {
+        let pipeline = player.pipeline();
+        let mut failure = None;
+        if let Some(message) = pipeline.bus().unwrap().timed_pop(gst::ClockTime::from_seconds(1)) {
+            match message.view() {
+                gst::MessageView::Error(err)
+                    if err.src().map(|src| player.on_recorder_error(src, &err.error().to_string())) == Some(true) => {},
+                gst::MessageView::Error(err) => {
+                    failure = Some(format!("error from {:?}: {} ({:?})",
+                                           err.src().map(|src| src.path_string()), err.error(), err.debug()));
+                },
+                gst::MessageView::Eos(_) if player.is_playback() => {
+                    println!("Video: end of recording, back to live video");
+                    if let Err(e) = player.stop_playback() {
+                        failure = Some(format!("couldn't return to live video: {:?}", e));
+                    }
+                },
+                gst::MessageView::Eos(_) => failure = Some("end of stream".to_owned()),
+                gst::MessageView::StateChanged(state) if message.src() == Some(pipeline.upcast_ref()) => {
+                    updater.update_video_playing(state.current() == gst::State::Playing);
+                },
+                _ if recorder::is_segment_closed(&message) => player.on_segment_closed(&message),
+                _ => {},
+            }
+        }
+
+        let current_frames = stats.frames.load(Ordering::Relaxed);
+        if current_frames != frames {
+            frames = current_frames;
+            last_progress = Instant::now();
+            if restarting {
+                println!("Video: stream restored");
+                restarting = false;
+                restart_delay = MIN_RESTART_DELAY;
+                updater.update_video_reconnecting(false);
+            }
+        } else if !player.is_playing() {
+            // no frames are expected on pause
+            last_progress = Instant::now();
+        } else if failure.is_none() && last_progress.elapsed() > stall_timeout {
+            failure = Some(format!("no frames for {:?}", stall_timeout));
+        }
+
+        if last_stats.0.elapsed() >= STATS_INTERVAL {
+            let fps = (current_frames - last_stats.1) as f32 / last_stats.0.elapsed().as_secs_f32();
+            last_stats = (Instant::now(), current_frames);
+            let (width, height) = player.last_frame()
+                .map(|frame| (frame.width(), frame.height())).unwrap_or_default();
+            let mut latency = gst::query::Latency::new();
+            let latency_ms = if pipeline.query(&mut latency) { latency.result().1.mseconds() as i32 } else { -1 };
+            updater.update_video_stats(StreamStatsUI {
+                fps,
+                width: width as i32,
+                height: height as i32,
+                dropped: stats.skipped.load(Ordering::Relaxed) as i32,
+                latency_ms
+            });
+        }
+
+        if let Some(reason) = failure {
+            println!("Video: {}, restarting pipeline in {:?}...", reason, restart_delay);
+            restarting = true;
+            updater.update_video_reconnecting(true);
+            player.stop();
+            thread::sleep(restart_delay);
+            restart_delay = (restart_delay * 2).min(MAX_RESTART_DELAY);
+            player.rebuild();
+            last_progress = Instant::now();
+        }
+    }
 }